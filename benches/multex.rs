@@ -1,11 +1,12 @@
 use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
-use multex::{Key, Multex64};
+use multex::{Key, Multex64, Multex64A, Strategy};
 use rayon::ThreadPoolBuilder;
 
 const COUNT: usize = usize::BITS as usize;
 const ITERATIONS: usize = 1_000;
 const BATCHES: [usize; 3] = [1, 10, 25];
 const OFFSETS: [usize; 7] = [1, 3, 7, 11, 13, 17, 19];
+const WORDS: usize = 4;
 
 fn standard_mutex(criterion: &mut Criterion) {
     for batch in BATCHES.iter() {
@@ -86,7 +87,7 @@ fn multex(criterion: &mut Criterion) {
                         for (i, key) in keys.iter_mut().enumerate() {
                             scope.spawn(move |_| {
                                 for _ in 0..ITERATIONS {
-                                    let mut guard = multex.lock_with(key, false);
+                                    let mut guard = multex.lock_with(key, false).unwrap();
                                     for guard in guard.iter_mut() {
                                         **guard.as_mut().unwrap() += i;
                                     }
@@ -101,5 +102,48 @@ fn multex(criterion: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, multex, parking_lot_mutex, standard_mutex);
+fn multex_words(criterion: &mut Criterion) {
+    for (name, strategy) in [
+        ("multex_all_or_nothing", Strategy::AllOrNothing),
+        ("multex_ordered", Strategy::Ordered),
+    ] {
+        for batch in BATCHES.iter() {
+            criterion.bench_with_input(BenchmarkId::new(name, batch), batch, |bencher, &batch| {
+                let pool = ThreadPoolBuilder::new().build().unwrap();
+                let multex =
+                    Multex64A::<_, WORDS>::with_strategy([(); COUNT * WORDS].map(|_| 0), strategy);
+                // The odd stride keeps the indices distinct and spreads every key over several words.
+                let mut keys = (0..batch)
+                    .map(|i| {
+                        Key::new(OFFSETS.map(|offset| (offset * 37 + i) % (COUNT * WORDS))).unwrap()
+                    })
+                    .collect::<Box<[_]>>();
+                bencher.iter(|| {
+                    pool.scope(|scope| {
+                        let multex = &multex;
+                        for (i, key) in keys.iter_mut().enumerate() {
+                            scope.spawn(move |_| {
+                                for _ in 0..ITERATIONS {
+                                    let mut guard = multex.lock_with(key, false).unwrap();
+                                    for guard in guard.iter_mut() {
+                                        **guard.as_mut().unwrap() += i;
+                                    }
+                                }
+                            });
+                        }
+                    })
+                });
+                black_box(multex);
+            });
+        }
+    }
+}
+
+criterion_group!(
+    benches,
+    multex,
+    multex_words,
+    parking_lot_mutex,
+    standard_mutex
+);
 criterion_main!(benches);
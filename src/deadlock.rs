@@ -0,0 +1,259 @@
+// The re-entrance check and the deadlock detector are enabled by separate features but share their bookkeeping.
+#![cfg_attr(
+    not(all(feature = "deadlock", feature = "reentrant")),
+    allow(dead_code)
+)]
+
+use crate::lock::Mask;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+/// The indices of a [`crate::Multex`] identified by the address of its state.
+struct Bits(usize, Vec<usize>);
+/// The bits taken by a guard, identified by the address of the state of its multex and an identifier of the guard.
+/// Since the bits of a guard are exclusive, the guard that holds an index is also found from the index alone.
+struct Hold(u64, Bits);
+struct Thread {
+    id: ThreadId,
+    name: Option<String>,
+    /// The guards that the thread took. Since a guard may have been sent to another thread since then, a cycle in the
+    /// wait-for graph is only a suspicion until it outlives [`CONFIRM`].
+    held: Vec<Hold>,
+    waiting: Option<Bits>,
+}
+
+/// How long a suspected deadlock must persist with the same guards before it is reported.
+pub(crate) const CONFIRM: Duration = Duration::from_secs(1);
+
+static THREADS: Mutex<Vec<Thread>> = Mutex::new(Vec::new());
+static GUARDS: AtomicU64 = AtomicU64::new(0);
+
+/// Records that the current thread took a guard that holds the bits of `mask` in `state`.
+pub(crate) fn hold<S, L: Mask>(state: &S, mask: &L) {
+    let indices = mask.indices().collect::<Vec<_>>();
+    let mut threads = threads();
+    let thread = current(&mut threads);
+    thread.waiting = None;
+    if !indices.is_empty() {
+        let id = GUARDS.fetch_add(1, Relaxed);
+        thread.held.push(Hold(id, Bits(address(state), indices)));
+    }
+}
+
+/// Forgets the bits of `mask` in `state`, regardless of the thread that took their guards since guards may be sent to
+/// other threads.
+pub(crate) fn release<S, L: Mask>(state: &S, mask: &L) {
+    let address = address(state);
+    let mut threads = threads();
+    for thread in threads.iter_mut() {
+        for Hold(_, Bits(held, indices)) in thread.held.iter_mut() {
+            if *held == address {
+                indices.retain(|&index| !mask.has(index));
+            }
+        }
+        thread
+            .held
+            .retain(|Hold(_, Bits(_, indices))| !indices.is_empty());
+    }
+    threads.retain(|thread| thread.waiting.is_some() || !thread.held.is_empty());
+}
+
+/// Returns the guards taken by the current thread that hold bits of `mask` in `state`, if they hold so many of them
+/// that fewer than `need` indices of `mask` can ever be taken. The thread should then wait for at most [`CONFIRM`]
+/// before checking again with these guards as `suspected`.
+///
+/// # Panics
+/// Panics with the conflicting indices if they are still held by the `suspected` guards, since the current thread
+/// then waits for itself.
+pub(crate) fn reenter<S, L: Mask>(
+    state: &S,
+    mask: &L,
+    need: usize,
+    suspected: Option<&[u64]>,
+) -> Option<Vec<u64>> {
+    let address = address(state);
+    let id = thread::current().id();
+    let threads = threads();
+    let thread = threads.iter().find(|thread| thread.id == id)?;
+    let mut guards = Vec::new();
+    let mut indices = Vec::new();
+    for Hold(guard, Bits(held, taken)) in &thread.held {
+        let count = indices.len();
+        if *held == address {
+            indices.extend(taken.iter().copied().filter(|&index| mask.has(index)));
+        }
+        if indices.len() > count {
+            guards.push(*guard);
+        }
+    }
+    if indices.is_empty() || mask.indices().count() - indices.len() >= need {
+        return None;
+    } else if suspected == Some(guards.as_slice()) {
+        let report = format!(
+            "Re-entrant lock detected.\n- Thread {} already holds indices {indices:?} of multex '{address:#x}'.",
+            name(thread)
+        );
+        drop(threads);
+        panic!("{report}");
+    }
+    Some(guards)
+}
+
+/// Records that the current thread is about to wait for the bits of `mask` in `state` and returns the guards that
+/// close a cycle in the wait-for graph, if any. The thread should then wait for at most [`CONFIRM`] before checking
+/// again with these guards as `suspected`.
+///
+/// # Panics
+/// Panics with a report of the cycle if it is still closed by the `suspected` guards, since they then wait for each
+/// other.
+pub(crate) fn wait<S, L: Mask>(state: &S, mask: &L, suspected: Option<&[u64]>) -> Option<Vec<u64>> {
+    let mut threads = threads();
+    current(&mut threads).waiting = Some(Bits(address(state), mask.indices().collect()));
+    let path = cycle(&threads)?;
+    let guards = guards(&threads, &path);
+    if suspected == Some(guards.as_slice()) {
+        let report = report(&threads, &path);
+        threads[path[0]].waiting = None;
+        drop(threads);
+        panic!("{report}");
+    }
+    Some(guards)
+}
+
+/// Records that the current thread is no longer waiting.
+pub(crate) fn cancel() {
+    let id = thread::current().id();
+    let mut threads = threads();
+    if let Some(thread) = threads.iter_mut().find(|thread| thread.id == id) {
+        thread.waiting = None;
+    }
+    threads.retain(|thread| thread.waiting.is_some() || !thread.held.is_empty());
+}
+
+/// Searches a path of waiting threads that leads from the current thread back to it.
+fn cycle(threads: &[Thread]) -> Option<Vec<usize>> {
+    let id = thread::current().id();
+    let Some(start) = threads.iter().position(|thread| thread.id == id) else {
+        unreachable!()
+    };
+    let mut path = vec![start];
+    let mut visited = vec![false; threads.len()];
+    search(threads, &mut path, &mut visited).then_some(path)
+}
+
+/// Returns the identifiers of the guards that block the threads of `path`.
+fn guards(threads: &[Thread], path: &[usize]) -> Vec<u64> {
+    let mut guards = Vec::new();
+    for (index, &waiter) in path.iter().enumerate() {
+        let holder = path[(index + 1) % path.len()];
+        let Some(Bits(address, indices)) = &threads[waiter].waiting else {
+            unreachable!()
+        };
+        for Hold(id, Bits(held, taken)) in &threads[holder].held {
+            if held == address && taken.iter().any(|index| indices.contains(index)) {
+                guards.push(*id);
+            }
+        }
+    }
+    guards
+}
+
+fn threads() -> MutexGuard<'static, Vec<Thread>> {
+    THREADS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn current(threads: &mut Vec<Thread>) -> &mut Thread {
+    let current = thread::current();
+    match threads.iter().position(|thread| thread.id == current.id()) {
+        Some(index) => &mut threads[index],
+        None => {
+            threads.push(Thread {
+                id: current.id(),
+                name: current.name().map(String::from),
+                held: Vec::new(),
+                waiting: None,
+            });
+            let Some(thread) = threads.last_mut() else {
+                unreachable!()
+            };
+            thread
+        }
+    }
+}
+
+#[inline]
+fn address<S>(state: &S) -> usize {
+    state as *const S as usize
+}
+
+/// Returns the indices that `waiter` waits for and that `holder` holds.
+fn blocked(waiter: &Thread, holder: &Thread) -> Option<(usize, Vec<usize>)> {
+    let Bits(address, indices) = waiter.waiting.as_ref()?;
+    let blocked = holder
+        .held
+        .iter()
+        .filter(|Hold(_, Bits(held, _))| held == address)
+        .flat_map(|Hold(_, Bits(_, held))| {
+            held.iter().copied().filter(|index| indices.contains(index))
+        })
+        .collect::<Vec<_>>();
+    if blocked.is_empty() {
+        None
+    } else {
+        Some((*address, blocked))
+    }
+}
+
+/// Depth-first search of a path of waiting threads that leads back to the first thread of `path`.
+fn search(threads: &[Thread], path: &mut Vec<usize>, visited: &mut [bool]) -> bool {
+    let waiter = path[path.len() - 1];
+    for holder in 0..threads.len() {
+        if blocked(&threads[waiter], &threads[holder]).is_none() {
+            continue;
+        } else if holder == path[0] {
+            return true;
+        } else if visited[holder] {
+            continue;
+        }
+
+        visited[holder] = true;
+        path.push(holder);
+        if search(threads, path, visited) {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+fn report(threads: &[Thread], path: &[usize]) -> String {
+    let mut report = String::from("Deadlock detected.");
+    for (index, &waiter) in path.iter().enumerate() {
+        let holder = path[(index + 1) % path.len()];
+        let (waiter, holder) = (&threads[waiter], &threads[holder]);
+        let Some((address, indices)) = blocked(waiter, holder) else {
+            unreachable!()
+        };
+        let _ = write!(
+            report,
+            "\n- Thread {} waits for indices {indices:?} of multex '{address:#x}' held by thread {}.",
+            name(waiter),
+            name(holder),
+        );
+    }
+    report
+}
+
+fn name(thread: &Thread) -> String {
+    match &thread.name {
+        Some(name) => format!("'{name}' ({:?})", thread.id),
+        None => format!("{:?}", thread.id),
+    }
+}
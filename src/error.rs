@@ -0,0 +1,42 @@
+use thiserror::Error;
+
+/// The reasons for which taking the indices of a [`crate::Multex`] or a [`crate::RwMultex`] can fail. `G` is the guard
+/// that is handed back along with a poisoned lock and `L` is the mask of the multex.
+#[derive(Debug, Error)]
+pub enum LockError<G = (), L = ()> {
+    /// The indices `conflicting` were found locked by another guard and the lock was not allowed to wait for them.
+    #[error("The indices are locked by another guard.")]
+    WouldBlock { conflicting: L },
+    #[error("The indices could not be locked in time.")]
+    TimedOut,
+    /// The indices were held by a guard that was dropped during a panic. The guard is still handed out.
+    #[error("The indices were held by a guard that was dropped during a panic.")]
+    Poisoned(G),
+    #[error("Invalid index '{index}'.")]
+    InvalidIndex { index: usize },
+    /// The key has no index, so a lock that must take at least one of them can never succeed.
+    #[error("The key has no index.")]
+    EmptyKey,
+    /// The multex was closed with [`crate::Multex::close`] before the lock started, so it hands out no more indices.
+    #[error("The multex is closed.")]
+    Closed,
+    /// The multex was closed while the lock was waiting, such that the indices it may have taken were released.
+    #[error("The lock was cancelled.")]
+    Cancelled,
+}
+
+impl<G, L> LockError<G, L> {
+    /// Replaces the guard of a [`LockError::Poisoned`] error by `map(guard)`.
+    #[inline]
+    pub fn map<U, F: FnOnce(G) -> U>(self, map: F) -> LockError<U, L> {
+        match self {
+            LockError::WouldBlock { conflicting } => LockError::WouldBlock { conflicting },
+            LockError::TimedOut => LockError::TimedOut,
+            LockError::Poisoned(guard) => LockError::Poisoned(map(guard)),
+            LockError::InvalidIndex { index } => LockError::InvalidIndex { index },
+            LockError::EmptyKey => LockError::EmptyKey,
+            LockError::Closed => LockError::Closed,
+            LockError::Cancelled => LockError::Cancelled,
+        }
+    }
+}
@@ -1,7 +1,11 @@
-use crate::lock::Mask;
+use crate::{error::LockError, lock::Mask};
 use orn::*;
-use std::{alloc::Layout, array::from_fn, mem::transmute};
-use thiserror::*;
+use std::{
+    alloc::Layout,
+    array::from_fn,
+    mem::transmute,
+    ptr::{slice_from_raw_parts, slice_from_raw_parts_mut},
+};
 
 pub struct Key<L, G> {
     pub(crate) mask: L,
@@ -14,32 +18,41 @@ pub struct At<const I: usize>;
 #[repr(C)]
 struct RawSlice<T: ?Sized>(*mut T, usize);
 #[repr(C)]
-struct RawVec<T: ?Sized>(*mut T, usize, usize);
-#[repr(C)]
 struct RawBox<T: ?Sized>(*mut T);
 
 pub trait Fold<T> {
     fn fold<S, E>(&self, state: S, fold: impl FnMut(S, T) -> Result<S, E>) -> Result<S, E>;
 }
 
+/// Produces the items of the indices of a key from the value of a multex.
+///
+/// # Safety
+/// `filter` must be called exactly once per index, in the order of the indices, and only the items of the indices
+/// that pass it may be produced. Out of bounds indices produce no item.
 pub unsafe trait Get<'a, T: ?Sized> {
     type Item;
+    type Read;
+    /// Produces mutable items for the indices that pass `filter`.
+    ///
+    /// # Safety
+    /// `items` must point to a live value and the caller must hold the exclusive bits of every index that passes
+    /// `filter`, such that no other reference reaches these items for as long as the produced ones live.
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, filter: F) -> Self::Item;
-}
-
-#[derive(Debug, Error)]
-#[error("Invalid index '{index}'.")]
-pub struct InvalidIndexError {
-    pub index: usize,
+    /// Produces shared items for the indices that pass `filter`.
+    ///
+    /// # Safety
+    /// `items` must point to a live value and the caller must hold the read bits of every index that passes `filter`,
+    /// such that no mutable reference reaches these items for as long as the produced ones live.
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, filter: F) -> Self::Read;
 }
 
 impl<M: Mask, F: Fold<usize>> Key<M, F> {
-    pub fn new(indices: F) -> Result<Self, InvalidIndexError> {
+    pub fn new(indices: F) -> Result<Self, LockError> {
         let mask = indices.fold(M::new(), |mut mask, index| {
             if mask.add(index) {
                 Ok(mask)
             } else {
-                Err(InvalidIndexError { index })
+                Err(LockError::InvalidIndex { index })
             }
         })?;
         Ok(Self {
@@ -80,6 +93,12 @@ impl<T, F: Fold<T>> Fold<T> for [F] {
     }
 }
 
+impl<T, F: Fold<T>> Fold<T> for Vec<F> {
+    fn fold<S, E>(&self, state: S, fold: impl FnMut(S, T) -> Result<S, E>) -> Result<S, E> {
+        self.as_slice().fold(state, fold)
+    }
+}
+
 impl<T, F: Fold<T> + ?Sized> Fold<T> for &F {
     fn fold<S, E>(&self, state: S, fold: impl FnMut(S, T) -> Result<S, E>) -> Result<S, E> {
         F::fold(self, state, fold)
@@ -94,35 +113,63 @@ impl<T, F: Fold<T> + ?Sized> Fold<T> for &mut F {
 
 unsafe impl<'a, T: 'a, const N: usize> Get<'a, [T; N]> for usize {
     type Item = Option<&'a mut T>;
+    type Read = Option<&'a T>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut [T; N], mut filter: F) -> Self::Item {
         let index = *self;
-        if index < N && filter(index) {
+        if filter(index) && index < N {
             Some(&mut *items.cast::<T>().add(index))
         } else {
             None
         }
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(
+        &self,
+        items: *const [T; N],
+        mut filter: F,
+    ) -> Self::Read {
+        let index = *self;
+        if filter(index) && index < N {
+            Some(&*items.cast::<T>().add(index))
+        } else {
+            None
+        }
+    }
 }
 
 unsafe impl<'a, T: 'a> Get<'a, [T]> for usize {
     type Item = Option<&'a mut T>;
+    type Read = Option<&'a T>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut [T], mut filter: F) -> Self::Item {
         let raw = transmute::<*mut [T], RawSlice<T>>(items);
         let index = *self;
-        if index < raw.1 && filter(index) {
+        if filter(index) && index < raw.1 {
             Some(&mut *raw.0.add(index))
         } else {
             None
         }
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const [T], mut filter: F) -> Self::Read {
+        let raw = transmute::<*const [T], RawSlice<T>>(items);
+        let index = *self;
+        if filter(index) && index < raw.1 {
+            Some(&*raw.0.add(index))
+        } else {
+            None
+        }
+    }
 }
 
 unsafe impl<'a, T: 'a, const N: usize> Get<'a, Box<[T; N]>> for usize {
     type Item = Option<&'a mut T>;
+    type Read = Option<&'a T>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(
@@ -133,27 +180,55 @@ unsafe impl<'a, T: 'a, const N: usize> Get<'a, Box<[T; N]>> for usize {
         let raw = items.cast::<RawBox<[T; N]>>().read();
         <Self as Get<[T; N]>>::get(self, raw.0, filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(
+        &self,
+        items: *const Box<[T; N]>,
+        filter: F,
+    ) -> Self::Read {
+        let raw = items.cast::<RawBox<[T; N]>>().read();
+        <Self as Get<[T; N]>>::read(self, raw.0, filter)
+    }
 }
 
 unsafe impl<'a, T: 'a> Get<'a, Box<[T]>> for usize {
     type Item = Option<&'a mut T>;
+    type Read = Option<&'a T>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut Box<[T]>, filter: F) -> Self::Item {
         let raw = items.cast::<RawBox<[T]>>().read();
         <Self as Get<[T]>>::get(self, raw.0, filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(
+        &self,
+        items: *const Box<[T]>,
+        filter: F,
+    ) -> Self::Read {
+        let raw = items.cast::<RawBox<[T]>>().read();
+        <Self as Get<[T]>>::read(self, raw.0, filter)
+    }
 }
 
 unsafe impl<'a, T: 'a> Get<'a, Vec<T>> for usize {
     type Item = Option<&'a mut T>;
+    type Read = Option<&'a T>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut Vec<T>, filter: F) -> Self::Item {
-        let raw = items.cast::<RawVec<T>>().read();
-        let slice = transmute::<RawSlice<T>, *mut [T]>(RawSlice(raw.0, raw.2));
+        // The field order of a `Vec` is unspecified, so its slice must be retrieved through its methods.
+        let slice = slice_from_raw_parts_mut((*items).as_mut_ptr(), (*items).len());
         <Self as Get<[T]>>::get(self, slice, filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const Vec<T>, filter: F) -> Self::Read {
+        let slice = slice_from_raw_parts((*items).as_ptr(), (*items).len());
+        <Self as Get<[T]>>::read(self, slice, filter)
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a> Get<'a, &'a mut T> for usize
@@ -161,11 +236,21 @@ where
     Self: Get<'a, T>,
 {
     type Item = <Self as Get<'a, T>>::Item;
+    type Read = <Self as Get<'a, T>>::Read;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut &'a mut T, filter: F) -> Self::Item {
         <Self as Get<T>>::get(self, items.read(), filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(
+        &self,
+        items: *const &'a mut T,
+        filter: F,
+    ) -> Self::Read {
+        <Self as Get<T>>::read(self, items.cast::<*const T>().read(), filter)
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a> Get<'a, *mut T> for usize
@@ -173,15 +258,22 @@ where
     Self: Get<'a, T>,
 {
     type Item = <Self as Get<'a, T>>::Item;
+    type Read = <Self as Get<'a, T>>::Read;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut *mut T, filter: F) -> Self::Item {
         <Self as Get<T>>::get(self, items.read(), filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const *mut T, filter: F) -> Self::Read {
+        <Self as Get<T>>::read(self, items.read(), filter)
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T>> Get<'a, T> for [G] {
     type Item = Vec<G::Item>;
+    type Read = Vec<G::Read>;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, mut filter: F) -> Self::Item {
@@ -191,47 +283,103 @@ unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T>> Get<'a, T> for [G] {
         }
         values
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, mut filter: F) -> Self::Read {
+        let mut values = Vec::with_capacity(self.len());
+        for get in self {
+            values.push(get.read(items, &mut filter));
+        }
+        values
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T>, const N: usize> Get<'a, T> for [G; N] {
     type Item = [G::Item; N];
+    type Read = [G::Read; N];
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, mut filter: F) -> Self::Item {
         from_fn(|index| self[index].get(items, &mut filter))
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, mut filter: F) -> Self::Read {
+        from_fn(|index| self[index].read(items, &mut filter))
+    }
+}
+
+unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T>> Get<'a, T> for Vec<G> {
+    type Item = Vec<G::Item>;
+    type Read = Vec<G::Read>;
+
+    #[inline]
+    unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, filter: F) -> Self::Item {
+        <[G] as Get<T>>::get(self, items, filter)
+    }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, filter: F) -> Self::Read {
+        <[G] as Get<T>>::read(self, items, filter)
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T> + ?Sized> Get<'a, T> for &G {
     type Item = G::Item;
+    type Read = G::Read;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, filter: F) -> Self::Item {
         G::get(self, items, filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, filter: F) -> Self::Read {
+        G::read(self, items, filter)
+    }
 }
 
 unsafe impl<'a, T: ?Sized + 'a, G: Get<'a, T> + ?Sized> Get<'a, T> for &mut G {
     type Item = G::Item;
+    type Read = G::Read;
 
     #[inline]
     unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut T, filter: F) -> Self::Item {
         G::get(self, items, filter)
     }
+
+    #[inline]
+    unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const T, filter: F) -> Self::Read {
+        G::read(self, items, filter)
+    }
 }
 
 macro_rules! tuples {
     ($n:expr, $or:ident $(, $tn:ident, $ti:ident, $i:tt)*) => {
         unsafe impl<'a, $($tn: 'a,)*> Get<'a, ($($tn,)*)> for usize {
             type Item = Option<$or<$(&'a mut $tn),*>>;
+            type Read = Option<$or<$(&'a $tn),*>>;
 
             #[inline]
             unsafe fn get<F: FnMut(usize) -> bool>(&self, _items: *mut ($($tn,)*), mut _filter: F) -> Self::Item {
                 let index = *self;
+                let _taken = _filter(index);
+                let mut _layout = Layout::new::<()>();
+                let _offsets = ($({ let pair = _layout.extend(Layout::new::<$tn>()).unwrap(); _layout = pair.0; pair.1 },)*);
+                match index {
+                    $($i if _taken => Some($or::$tn(unsafe { &mut *_items.cast::<u8>().add(_offsets.$i).cast::<$tn>() })),)*
+                    _ => None,
+                }
+            }
+
+            #[inline]
+            unsafe fn read<F: FnMut(usize) -> bool>(&self, _items: *const ($($tn,)*), mut _filter: F) -> Self::Read {
+                let index = *self;
+                let _taken = _filter(index);
                 let mut _layout = Layout::new::<()>();
                 let _offsets = ($({ let pair = _layout.extend(Layout::new::<$tn>()).unwrap(); _layout = pair.0; pair.1 },)*);
                 match index {
-                    $($i if _filter($i) => Some($or::$tn(unsafe { &mut *_items.cast::<u8>().add(_offsets.$i).cast::<$tn>() })),)*
+                    $($i if _taken => Some($or::$tn(unsafe { &*_items.cast::<u8>().add(_offsets.$i).cast::<$tn>() })),)*
                     _ => None,
                 }
             }
@@ -239,12 +387,19 @@ macro_rules! tuples {
 
         unsafe impl<'a, T $(, $ti: Get<'a, T>)*> Get<'a, T> for ($($ti,)*) {
             type Item = ($($ti::Item,)*);
+            type Read = ($($ti::Read,)*);
 
             #[inline]
             unsafe fn get<F: FnMut(usize) -> bool>(&self, _items: *mut T, mut _filter: F) -> Self::Item {
                 #[allow(clippy::unused_unit)]
                 ($(self.$i.get(_items, &mut _filter),)*)
             }
+
+            #[inline]
+            unsafe fn read<F: FnMut(usize) -> bool>(&self, _items: *const T, mut _filter: F) -> Self::Read {
+                #[allow(clippy::unused_unit)]
+                ($(self.$i.read(_items, &mut _filter),)*)
+            }
         }
 
 
@@ -263,6 +418,7 @@ macro_rules! at {
     (NEST $t:ident, $i:tt [$($ts:ident),+]) => {
         unsafe impl<'a, $($ts: 'a),+> Get<'a, ($($ts,)+)> for At<$i> {
             type Item = Option<&'a mut $t>;
+            type Read = Option<&'a $t>;
 
             #[inline]
             unsafe fn get<F: FnMut(usize) -> bool>(&self, items: *mut ($($ts,)+), mut filter: F) -> Self::Item {
@@ -274,6 +430,17 @@ macro_rules! at {
                     None
                 }
             }
+
+            #[inline]
+            unsafe fn read<F: FnMut(usize) -> bool>(&self, items: *const ($($ts,)+), mut filter: F) -> Self::Read {
+                let mut _layout = Layout::new::<()>();
+                let offsets = ($({ let pair = _layout.extend(Layout::new::<$ts>()).unwrap(); _layout = pair.0; pair.1 },)+);
+                if filter($i) {
+                    Some(unsafe { &*items.cast::<u8>().add(offsets.$i).cast::<$t>() })
+                } else {
+                    None
+                }
+            }
         }
     };
 }
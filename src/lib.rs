@@ -1,31 +1,32 @@
+#[cfg(any(feature = "deadlock", feature = "reentrant"))]
+mod deadlock;
+mod error;
 pub mod key;
 pub mod lock;
 mod multex;
+mod queue;
 mod system;
 
+pub use error::LockError;
 pub use key::{At, Key};
 pub use multex::{
-    Guard, Multex, Multex16, Multex16A, Multex16V, Multex32, Multex32A, Multex32V, Multex64,
-    Multex64A, Multex64V, Multex8, Multex8A, Multex8V, MultexA, MultexV,
+    acquire_many, lock_many, AcquireFuture, AcquireWithFuture, ArcGuard, Fairness, Guard,
+    LockFuture, LockWithFuture, Many, Multex, Multex16, Multex16A, Multex16V, Multex32, Multex32A,
+    Multex32V, Multex64, Multex64A, Multex64V, Multex8, Multex8A, Multex8V, MultexA, MultexCondvar,
+    MultexV, OwnedGuard, ReadGuard, RwMultex, RwMultex16, RwMultex16A, RwMultex16V, RwMultex32,
+    RwMultex32A, RwMultex32V, RwMultex64, RwMultex64A, RwMultex64V, RwMultex8, RwMultex8A,
+    RwMultex8V, RwMultexA, RwMultexV, Strategy, UpgradableGuard, UpgradedGuard, WriteGuard,
 };
 
 /*
     TODO:
-    - Given a 'Guard<T>', allow to 'locK_with' and 'try_locK_with' additional resources.
-        - 'lock_with' will first try to take the additional locks and if it fails, it will need to
-        drop all of its locks and take them all at once on wake.
     - There's probably a way to implement 'LockAll' for 'Vec<T>' without allocations.
-    - Add timeouts for `Multex` methods that might wait.
     - Compose keys with lock settings. This must modify the return type.
         - Key<_, At<0>> returns '&mut T'.
         - Key<_, Partial<At<0>>> returns 'Option<&mut T>'.
         - Key<_, Timeout<(At<0>, At<1>)> returns 'Option<(&mut T, &mut T)>'.
         - Is there a way to prevent redundance (ex: Partial<Partial<At<0>>>)?
         - Using types, reduces the size of the key.
-    - System calls should take a 'u32' as the mask parameter.
-
-    - How to get deadlock detection?
-    - How to get re-entrant detection?
 */
 
 // mod dynamic {
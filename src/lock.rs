@@ -1,14 +1,20 @@
-use crate::system;
+use crate::{error::LockError, system};
 use std::{
     alloc::{alloc, dealloc, Layout},
+    array::from_fn,
+    borrow::Borrow,
+    iter,
     mem::size_of,
     ops::Deref,
     ptr::{drop_in_place, null_mut},
     slice::from_raw_parts,
     sync::{
-        atomic::{AtomicPtr, AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering::*},
+        atomic::{
+            fence, AtomicPtr, AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering::*,
+        },
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 pub trait Mask: Sized {
@@ -17,28 +23,191 @@ pub trait Mask: Sized {
     fn add(&mut self, index: usize) -> bool;
     fn remove(&mut self, index: usize) -> bool;
     fn clear(&mut self);
+    fn merge(&mut self, other: &Self);
+    fn overlaps(&self, other: &Self) -> bool;
+    fn indices(&self) -> impl Iterator<Item = usize> + '_;
 }
 
+/// Takes and releases the bits of a mask in a shared state.
+///
+/// # Safety
+/// A lock that succeeds must have taken all the bits that it stores in `taken`, and no other lock of the same state
+/// may take any of them until they are released with [`Lock::unlock`], since the guards hand out references on that
+/// basis. A lock that fails must hold none of the bits of the mask.
 pub unsafe trait Lock: Mask {
     type State;
     const NEW: Self::State;
 
-    fn lock(&self, state: &Self::State, taken: &mut Self, partial: bool, wait: bool) -> bool;
+    /// Takes all the bits of this mask and stores them in `taken`. When `partial` is set, takes the bits that are free
+    /// right now instead and stores exactly those in `taken`, without ever waiting.
+    fn lock(
+        &self,
+        state: &Self::State,
+        taken: &mut Self,
+        partial: bool,
+        wait: Wait,
+    ) -> Locked<Self>;
     fn unlock(&self, state: &Self::State, wake: bool) -> bool;
     fn is_locked(&self, state: &Self::State, partial: bool) -> bool;
+    /// Returns the bits of this mask that are currently locked in `state`.
+    fn conflicts(&self, state: &Self::State) -> Self;
+    /// Takes a single free bit of this mask and stores it in `taken`. Waits only while all the bits are locked and
+    /// fails right away with [`LockError::EmptyKey`] if the mask is empty.
+    fn lock_any(&self, state: &Self::State, taken: &mut Self, wait: Wait) -> Locked<Self>;
+    /// Takes at most `max` of the free bits of this mask, lowest indices first, and stores them in `taken`. Waits
+    /// while fewer than `min` of the bits are free.
+    fn lock_some(
+        &self,
+        state: &Self::State,
+        taken: &mut Self,
+        min: usize,
+        max: usize,
+        wait: Wait,
+    ) -> Locked<Self>;
+    /// Same as [`Lock::lock`], but takes the words of a mask that spans several of them in ascending order and waits
+    /// for a conflicting word while holding the earlier ones.
+    #[inline]
+    fn lock_ordered(&self, state: &Self::State, taken: &mut Self, wait: Wait) -> Locked<Self> {
+        self.lock(state, taken, false, wait)
+    }
 }
 
+/// The outcome of taking the bits of a mask `L`, along with the reason of a failure.
+pub type Locked<L> = Result<(), LockError<(), L>>;
+
 pub trait LockAll: Lock {
     const ALL: Self;
 }
 
+/// Determines how long a lock may wait for its bits to be released.
+#[derive(Clone, Copy, Debug)]
+pub enum Wait {
+    Never,
+    Forever,
+    Until(Instant),
+}
+
+/// Each index of a [`RwLock`] has its own counter that holds either a single writer or any number of readers.
+///
+/// # Safety
+/// `visit` must provide a distinct counter for every index of the mask and always the same one for a given index.
+pub unsafe trait RwLock: Mask {
+    type Counts;
+    const COUNTS: Self::Counts;
+
+    fn visit<'a, F: FnMut(usize, &'a AtomicU32) -> bool>(
+        &self,
+        counts: &'a Self::Counts,
+        visit: F,
+    ) -> bool;
+
+    #[inline]
+    fn read(&self, counts: &Self::Counts, taken: &mut Self, wait: Wait) -> Locked<Self> {
+        acquire(self, counts, taken, wait, read, unread)
+    }
+
+    #[inline]
+    fn write(&self, counts: &Self::Counts, taken: &mut Self, wait: Wait) -> Locked<Self> {
+        acquire(self, counts, taken, wait, write, unwrite)
+    }
+
+    #[inline]
+    fn upgradable(&self, counts: &Self::Counts, taken: &mut Self, wait: Wait) -> Locked<Self> {
+        acquire(self, counts, taken, wait, upgradable, unupgrade)
+    }
+
+    /// Turns the upgradable locks of this mask into write locks. New readers are turned away as soon as the upgrade
+    /// begins and the upgrade completes once the current readers are gone.
+    #[inline]
+    fn upgrade(&self, counts: &Self::Counts) {
+        self.visit(counts, |_, count| {
+            count.fetch_or(WRITE, Acquire);
+            true
+        });
+        self.visit(counts, |_, count| {
+            loop {
+                let value = count.load(Acquire);
+                if value & READS == 0 {
+                    break;
+                }
+                system::wait(count, value, u64::MAX, None);
+            }
+            count.fetch_and(!UPGRADE, Relaxed);
+            true
+        });
+    }
+
+    #[inline]
+    fn try_upgrade(&self, counts: &Self::Counts) -> bool {
+        let mut upgraded = Self::new();
+        let done = self.visit(counts, |index, count| {
+            match count.compare_exchange(UPGRADE, WRITE, Acquire, Relaxed) {
+                Ok(_) => upgraded.add(index),
+                Err(_) => false,
+            }
+        });
+        if !done {
+            upgraded.downgrade(counts);
+        }
+        done
+    }
+
+    /// Turns the write locks of this mask back into upgradable locks and lets the waiting readers in.
+    #[inline]
+    fn downgrade(&self, counts: &Self::Counts) {
+        self.visit(counts, |_, count| {
+            count.fetch_xor(WRITE | UPGRADE, Release);
+            system::wake(count, u64::MAX);
+            true
+        });
+    }
+
+    #[inline]
+    fn unlock_read(&self, counts: &Self::Counts) {
+        self.visit(counts, |_, count| {
+            unread(count);
+            true
+        });
+    }
+
+    #[inline]
+    fn unlock_write(&self, counts: &Self::Counts) {
+        self.visit(counts, |_, count| {
+            unwrite(count);
+            true
+        });
+    }
+
+    #[inline]
+    fn unlock_upgradable(&self, counts: &Self::Counts) {
+        self.visit(counts, |_, count| {
+            unupgrade(count);
+            true
+        });
+    }
+}
+
 #[repr(transparent)]
 pub struct Same<T: ?Sized>(pub T);
-pub struct State<T>(AtomicPtr<Header<T>>, AtomicU32);
+pub struct State<T>(AtomicPtr<Header<T>>, Version);
+/// The version of a multex of several words that its blocked threads park on, and the number of threads that wait on
+/// the memory of the words themselves with `futex_waitv` instead, which its releases only wake when there is one. The
+/// version is only needed without `futex_waitv` or for the words of a `Vec` that are narrower than 32 bits.
+pub struct Version(AtomicU32, AtomicU32);
 
 struct Header<T>(usize, *mut Self);
 
 const WAIT: u32 = 1 << 0;
+const WRITE: u32 = 1 << 31;
+const UPGRADE: u32 = 1 << 30;
+const READS: u32 = !(WRITE | UPGRADE);
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNT: AtomicU32 = AtomicU32::new(0);
+
+impl Version {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self(AtomicU32::new(0), AtomicU32::new(0));
+}
 
 impl<T> Deref for Same<T> {
     type Target = T;
@@ -49,6 +218,13 @@ impl<T> Deref for Same<T> {
     }
 }
 
+impl<const N: usize> Default for Same<[AtomicU32; N]> {
+    #[inline]
+    fn default() -> Self {
+        Self(from_fn(|_| AtomicU32::new(0)))
+    }
+}
+
 macro_rules! lock {
     ($v:ty, $a:ty) => {
         unsafe impl Lock for $v {
@@ -61,8 +237,8 @@ macro_rules! lock {
                 state: &Self::State,
                 taken: &mut Self,
                 partial: bool,
-                wait: bool,
-            ) -> bool {
+                wait: Wait,
+            ) -> Locked<Self> {
                 #[inline]
                 fn lock_once(state: &$a, mask: $v) -> Result<$v, $v> {
                     state.fetch_update(Acquire, Relaxed, |state| {
@@ -75,31 +251,33 @@ macro_rules! lock {
                 }
 
                 #[inline]
-                fn lock_wait(state: &$a, mask: $v) -> $v {
+                fn lock_wait(state: &$a, mask: $v, wait: Wait) -> bool {
                     loop {
                         match lock_once(state, mask) {
-                            Ok(_) => break mask,
-                            Err(value) => system::wait(state, value, mask),
+                            Ok(_) => break true,
+                            Err(value) => {
+                                if !wait.park(state, value, mask as u64) {
+                                    break false;
+                                }
+                            }
                         }
                     }
                 }
 
                 let mask = *self;
-                if mask == 0 {
+                let locked = if mask == 0 {
                     *taken = 0;
                     true
                 } else if partial {
-                    *taken = state.fetch_or(mask, Acquire) ^ mask & mask;
-                    true
-                } else if wait {
-                    *taken = lock_wait(state, mask);
+                    *taken = !state.fetch_or(mask, Acquire) & mask;
                     true
-                } else if lock_once(state, mask).is_ok() {
+                } else if lock_wait(state, mask, wait) {
                     *taken = mask;
                     true
                 } else {
                     false
-                }
+                };
+                wait.check(locked, || self.conflicts(state))
             }
 
             #[inline]
@@ -109,11 +287,13 @@ macro_rules! lock {
                     return false;
                 }
 
-                let value = state.fetch_and(!mask, Release);
+                // Sequentially consistent such that the waiters of the multex that raise a flag and then try again
+                // either see this release or are seen by it.
+                let value = state.fetch_and(!mask, SeqCst);
                 if value & mask == 0 {
                     false
                 } else if wake {
-                    system::wake(state, mask);
+                    system::wake(state, mask as u64);
                     true
                 } else {
                     true
@@ -131,12 +311,116 @@ macro_rules! lock {
                     state.load(Relaxed) & mask == mask
                 }
             }
+
+            #[inline]
+            fn conflicts(&self, state: &Self::State) -> Self {
+                *self & state.load(Relaxed)
+            }
+
+            fn lock_some(
+                &self,
+                state: &Self::State,
+                taken: &mut Self,
+                min: usize,
+                max: usize,
+                wait: Wait,
+            ) -> Locked<Self> {
+                #[inline]
+                fn first(mut bits: $v, count: usize) -> $v {
+                    let mut first = 0;
+                    for _ in 0..count {
+                        if bits == 0 {
+                            break;
+                        }
+                        let low = bits & bits.wrapping_neg();
+                        first |= low;
+                        bits ^= low;
+                    }
+                    first
+                }
+
+                let mask = *self;
+                loop {
+                    let result = state.fetch_update(Acquire, Relaxed, |value| {
+                        let bits = first(mask & !value, max);
+                        if (bits.count_ones() as usize) < min {
+                            None
+                        } else {
+                            Some(value | bits)
+                        }
+                    });
+                    match result {
+                        Ok(value) => {
+                            *taken = first(mask & !value, max);
+                            break Ok(());
+                        }
+                        Err(value) => {
+                            if !wait.park(state, value, mask as u64) {
+                                break wait.check(false, || self.conflicts(state));
+                            }
+                        }
+                    }
+                }
+            }
+
+            fn lock_any(&self, state: &Self::State, taken: &mut Self, wait: Wait) -> Locked<Self> {
+                let mask = *self;
+                if mask == 0 {
+                    return Err(LockError::EmptyKey);
+                }
+
+                loop {
+                    let result = state.fetch_update(Acquire, Relaxed, |value| {
+                        let free = mask & !value;
+                        if free == 0 {
+                            None
+                        } else {
+                            Some(value | free & free.wrapping_neg())
+                        }
+                    });
+                    match result {
+                        Ok(value) => {
+                            let free = mask & !value;
+                            *taken = free & free.wrapping_neg();
+                            break Ok(());
+                        }
+                        Err(value) => {
+                            if !wait.park(state, value, mask as u64) {
+                                break wait.check(false, || self.conflicts(state));
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         impl LockAll for $v {
             const ALL: Self = !0;
         }
 
+        unsafe impl RwLock for $v {
+            type Counts = Same<[AtomicU32; <$v>::BITS as usize]>;
+            #[allow(clippy::declare_interior_mutable_const)]
+            const COUNTS: Self::Counts = Same([COUNT; <$v>::BITS as usize]);
+
+            #[inline]
+            fn visit<'a, F: FnMut(usize, &'a AtomicU32) -> bool>(
+                &self,
+                counts: &'a Self::Counts,
+                mut visit: F,
+            ) -> bool {
+                let mut mask = *self;
+                while mask != 0 {
+                    let index = mask.trailing_zeros() as usize;
+                    if !visit(index, &counts[index]) {
+                        return false;
+                    }
+                    mask &= mask - 1;
+                }
+                true
+            }
+        }
+
         impl Mask for $v {
             #[inline]
             fn new() -> Self {
@@ -175,6 +459,30 @@ macro_rules! lock {
             fn clear(&mut self) {
                 *self = 0;
             }
+
+            #[inline]
+            fn merge(&mut self, other: &Self) {
+                *self |= *other;
+            }
+
+            #[inline]
+            fn overlaps(&self, other: &Self) -> bool {
+                *self & *other != 0
+            }
+
+            #[inline]
+            fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+                let mut mask = *self;
+                iter::from_fn(move || {
+                    if mask == 0 {
+                        None
+                    } else {
+                        let index = mask.trailing_zeros() as usize;
+                        mask &= mask - 1;
+                        Some(index)
+                    }
+                })
+            }
         }
 
         impl Same<$a> {
@@ -183,19 +491,20 @@ macro_rules! lock {
         }
 
         unsafe impl<const N: usize> Lock for [$v; N] {
-            type State = ([Same<$a>; N], AtomicU32);
+            type State = ([Same<$a>; N], Version);
             #[allow(clippy::declare_interior_mutable_const)]
-            const NEW: Self::State = ([Same::<$a>::NEW; N], AtomicU32::new(0));
+            const NEW: Self::State = ([Same::<$a>::NEW; N], Version::NEW);
 
             #[inline]
             fn lock(
                 &self,
-                (states, version): &Self::State,
+                state: &Self::State,
                 taken: &mut Self,
                 partial: bool,
-                wait: bool,
-            ) -> bool {
-                lock_all(states, version, self, taken, partial, wait)
+                wait: Wait,
+            ) -> Locked<Self> {
+                let locked = lock_all(&state.0, &state.1, self, taken, partial, wait);
+                wait.check(locked, || self.conflicts(state))
             }
 
             #[inline]
@@ -207,12 +516,65 @@ macro_rules! lock {
             fn is_locked(&self, (states, _): &Self::State, partial: bool) -> bool {
                 are_locked(states, self, partial)
             }
+
+            #[inline]
+            fn conflicts(&self, (states, _): &Self::State) -> Self {
+                from_fn(|index| self[index].conflicts(&states[index]))
+            }
+
+            #[inline]
+            fn lock_any(&self, state: &Self::State, taken: &mut Self, wait: Wait) -> Locked<Self> {
+                if self.indices().next().is_none() {
+                    return Err(LockError::EmptyKey);
+                }
+                let locked = lock_any_all(&state.0, &state.1, self, taken, wait);
+                wait.check(locked, || self.conflicts(state))
+            }
+
+            #[inline]
+            fn lock_some(
+                &self,
+                state: &Self::State,
+                taken: &mut Self,
+                min: usize,
+                max: usize,
+                wait: Wait,
+            ) -> Locked<Self> {
+                let locked = lock_some_all(&state.0, &state.1, self, taken, (min, max), wait);
+                wait.check(locked, || self.conflicts(state))
+            }
+
+            #[inline]
+            fn lock_ordered(
+                &self,
+                state: &Self::State,
+                taken: &mut Self,
+                wait: Wait,
+            ) -> Locked<Self> {
+                let locked = lock_ordered_all(&state.0, &state.1, self, taken, wait);
+                wait.check(locked, || self.conflicts(state))
+            }
         }
 
         impl<const N: usize> LockAll for [$v; N] {
             const ALL: Self = [!0; N];
         }
 
+        unsafe impl<const N: usize> RwLock for [$v; N] {
+            type Counts = [<$v as RwLock>::Counts; N];
+            #[allow(clippy::declare_interior_mutable_const)]
+            const COUNTS: Self::Counts = [<$v as RwLock>::COUNTS; N];
+
+            #[inline]
+            fn visit<'a, F: FnMut(usize, &'a AtomicU32) -> bool>(
+                &self,
+                counts: &'a Self::Counts,
+                visit: F,
+            ) -> bool {
+                visit_all(counts, self, <$v>::BITS as usize, visit)
+            }
+        }
+
         impl<const N: usize> Mask for [$v; N] {
             #[inline]
             fn new() -> Self {
@@ -247,13 +609,35 @@ macro_rules! lock {
             fn clear(&mut self) {
                 *self = [0; N];
             }
+
+            #[inline]
+            fn merge(&mut self, other: &Self) {
+                for (mask, other) in self.iter_mut().zip(other) {
+                    mask.merge(other);
+                }
+            }
+
+            #[inline]
+            fn overlaps(&self, other: &Self) -> bool {
+                self.iter()
+                    .zip(other)
+                    .any(|(mask, other)| mask.overlaps(other))
+            }
+
+            #[inline]
+            fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+                self.iter().enumerate().flat_map(|(word, mask)| {
+                    mask.indices()
+                        .map(move |index| word * <$v>::BITS as usize + index)
+                })
+            }
         }
 
         // TODO: The implementation could be for `[T]`?
         unsafe impl Lock for Vec<$v> {
             type State = State<$a>;
             #[allow(clippy::declare_interior_mutable_const)]
-            const NEW: Self::State = State(AtomicPtr::new(null_mut()), AtomicU32::new(0));
+            const NEW: Self::State = State(AtomicPtr::new(null_mut()), Version::NEW);
 
             #[inline]
             fn lock(
@@ -261,11 +645,12 @@ macro_rules! lock {
                 state: &Self::State,
                 taken: &mut Self,
                 partial: bool,
-                wait: bool,
-            ) -> bool {
+                wait: Wait,
+            ) -> Locked<Self> {
                 taken.resize(self.len(), 0);
                 let states = load(&state.0, self.len());
-                lock_all(states, &state.1, self, taken, partial, wait)
+                let locked = lock_all(states, &state.1, self, taken, partial, wait);
+                wait.check(locked, || self.conflicts(state))
             }
 
             #[inline]
@@ -279,6 +664,70 @@ macro_rules! lock {
                 let states = load(state, self.len());
                 are_locked(states, self, partial)
             }
+
+            #[inline]
+            fn conflicts(&self, State(state, _): &Self::State) -> Self {
+                let states = load(state, self.len());
+                self.iter()
+                    .zip(states)
+                    .map(|(mask, state)| mask.conflicts(state))
+                    .collect()
+            }
+
+            #[inline]
+            fn lock_any(&self, state: &Self::State, taken: &mut Self, wait: Wait) -> Locked<Self> {
+                if self.indices().next().is_none() {
+                    return Err(LockError::EmptyKey);
+                }
+                taken.resize(self.len(), 0);
+                let states = load(&state.0, self.len());
+                let locked = lock_any_all(states, &state.1, self, taken, wait);
+                wait.check(locked, || self.conflicts(state))
+            }
+
+            #[inline]
+            fn lock_some(
+                &self,
+                state: &Self::State,
+                taken: &mut Self,
+                min: usize,
+                max: usize,
+                wait: Wait,
+            ) -> Locked<Self> {
+                taken.resize(self.len(), 0);
+                let states = load(&state.0, self.len());
+                let locked = lock_some_all(states, &state.1, self, taken, (min, max), wait);
+                wait.check(locked, || self.conflicts(state))
+            }
+
+            #[inline]
+            fn lock_ordered(
+                &self,
+                state: &Self::State,
+                taken: &mut Self,
+                wait: Wait,
+            ) -> Locked<Self> {
+                taken.resize(self.len(), 0);
+                let states = load(&state.0, self.len());
+                let locked = lock_ordered_all(states, &state.1, self, taken, wait);
+                wait.check(locked, || self.conflicts(state))
+            }
+        }
+
+        unsafe impl RwLock for Vec<$v> {
+            type Counts = State<<$v as RwLock>::Counts>;
+            #[allow(clippy::declare_interior_mutable_const)]
+            const COUNTS: Self::Counts = State(AtomicPtr::new(null_mut()), Version::NEW);
+
+            #[inline]
+            fn visit<'a, F: FnMut(usize, &'a AtomicU32) -> bool>(
+                &self,
+                State(counts, _): &'a Self::Counts,
+                visit: F,
+            ) -> bool {
+                let counts = load(counts, self.len());
+                visit_all(counts, self, <$v>::BITS as usize, visit)
+            }
         }
 
         impl Mask for Vec<$v> {
@@ -289,8 +738,8 @@ macro_rules! lock {
 
             #[inline]
             fn has(&self, index: usize) -> bool {
-                match self.get(index / usize::BITS as usize) {
-                    Some(mask) => mask.has(index % usize::BITS as usize),
+                match self.get(index / <$v>::BITS as usize) {
+                    Some(mask) => mask.has(index % <$v>::BITS as usize),
                     None => false,
                 }
             }
@@ -298,8 +747,8 @@ macro_rules! lock {
             #[inline]
             fn add(&mut self, index: usize) -> bool {
                 loop {
-                    match self.get_mut(index / usize::BITS as usize) {
-                        Some(mask) => break mask.add(index % usize::BITS as usize),
+                    match self.get_mut(index / <$v>::BITS as usize) {
+                        Some(mask) => break mask.add(index % <$v>::BITS as usize),
                         None => self.push(0),
                     }
                 }
@@ -307,8 +756,8 @@ macro_rules! lock {
 
             #[inline]
             fn remove(&mut self, index: usize) -> bool {
-                match self.get_mut(index / usize::BITS as usize) {
-                    Some(mask) => mask.remove(index % usize::BITS as usize),
+                match self.get_mut(index / <$v>::BITS as usize) {
+                    Some(mask) => mask.remove(index % <$v>::BITS as usize),
                     None => false,
                 }
             }
@@ -317,6 +766,31 @@ macro_rules! lock {
             fn clear(&mut self) {
                 self.clear()
             }
+
+            #[inline]
+            fn merge(&mut self, other: &Self) {
+                if self.len() < other.len() {
+                    self.resize(other.len(), 0);
+                }
+                for (mask, other) in self.iter_mut().zip(other) {
+                    mask.merge(other);
+                }
+            }
+
+            #[inline]
+            fn overlaps(&self, other: &Self) -> bool {
+                self.iter()
+                    .zip(other)
+                    .any(|(mask, other)| mask.overlaps(other))
+            }
+
+            #[inline]
+            fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+                self.iter().enumerate().flat_map(|(word, mask)| {
+                    mask.indices()
+                        .map(move |index| word * <$v>::BITS as usize + index)
+                })
+            }
         }
     };
 }
@@ -327,6 +801,150 @@ lock!(u32, AtomicU32);
 lock!(u64, AtomicU64);
 lock!(usize, AtomicUsize);
 
+/// The words of state that [`system::wait_any`] can wait on. Futexes are 32 bits wide, so the threads wait on the
+/// 32-bit words of memory that hold the state instead, see [`load_words`].
+trait Word {
+    /// Copies the bytes of the value of this word into `bytes`.
+    fn bytes(&self, bytes: &mut [u8]);
+}
+
+macro_rules! word {
+    ($($a:ty),*) => {
+        $(impl Word for $a {
+            #[inline]
+            fn bytes(&self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.load(Acquire).to_ne_bytes());
+            }
+        })*
+    };
+}
+
+word!(AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize);
+
+/// The 32-bit words of memory that futexes wait on for the `bits` of the state `word`, which are the halves of a
+/// 64-bit word that hold them or the aligned word that holds a narrower one.
+#[inline]
+fn blocks<W>(word: &W, bits: u64) -> impl Iterator<Item = *const u32> {
+    let size = size_of::<W>();
+    let offset = word as *const W as usize % size_of::<u32>();
+    let start = (word as *const W).cast::<u8>().wrapping_sub(offset);
+    let all = bits.to_ne_bytes();
+    let bytes = if cfg!(target_endian = "little") {
+        0..size
+    } else {
+        all.len() - size..all.len()
+    };
+    (0..(offset + size).div_ceil(size_of::<u32>())).filter_map(move |block| {
+        let range = block * size_of::<u32>()..(block + 1) * size_of::<u32>();
+        let held = range.clone().any(|byte| {
+            (offset..offset + size).contains(&byte) && all[bytes.start + byte - offset] != 0
+        });
+        held.then(|| start.wrapping_add(range.start).cast::<u32>())
+    })
+}
+
+/// Adds the [`blocks`] of the `bits` of `states[index]` to `words` along with their value. Fails if one of them also
+/// holds memory that is not a word of `states`, whose value is unknown, as for the words of a `Vec` that are narrower
+/// than 32 bits since each of them is allocated on its own.
+fn load_words<W: Word, D: Deref<Target = W>>(
+    states: &[D],
+    index: usize,
+    bits: u64,
+    words: &mut Vec<(*const u32, u32)>,
+) -> bool {
+    let word = &*states[index];
+    let size = size_of::<W>();
+    let offset = word as *const W as usize % size_of::<u32>();
+    let base = word as *const W as usize - offset;
+    let length = (offset + size).next_multiple_of(size_of::<u32>());
+    let mut bytes = [0; 2 * size_of::<u32>()];
+    let mut covered = 0;
+    let neighbors = size_of::<u32>() - 1;
+    for other in &states[index.saturating_sub(neighbors)..states.len().min(index + neighbors + 1)] {
+        let at = &**other as *const W as usize;
+        if at >= base && at + size <= base + length {
+            other.bytes(&mut bytes[at - base..at - base + size]);
+            covered += size;
+        }
+    }
+    if covered != length {
+        return false;
+    }
+
+    for address in blocks(word, bits) {
+        let at = address as usize - base;
+        let mut value = [0; size_of::<u32>()];
+        value.copy_from_slice(&bytes[at..at + size_of::<u32>()]);
+        if !words.iter().any(|&(other, _)| other == address) {
+            words.push((address, u32::from_ne_bytes(value)));
+        }
+    }
+    true
+}
+
+/// The bits of `mask` as an integer.
+#[inline]
+fn bits<L: Lock>(mask: &L) -> u64 {
+    mask.indices().fold(0, |bits, index| bits | 1 << index)
+}
+
+impl Wait {
+    #[inline]
+    pub fn after(timeout: Duration) -> Self {
+        match Instant::now().checked_add(timeout) {
+            Some(until) => Wait::Until(until),
+            None => Wait::Forever,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn expired(&self) -> bool {
+        match self {
+            Wait::Never => true,
+            Wait::Forever => false,
+            Wait::Until(until) => Instant::now() >= *until,
+        }
+    }
+
+    /// Waits for `state` to change from `value`. Returns `false` if the lock must give up instead of trying again.
+    #[inline]
+    fn park<S, V>(&self, state: &S, value: V, mask: u64) -> bool {
+        if self.expired() {
+            false
+        } else {
+            system::wait(state, value, mask, self.until());
+            true
+        }
+    }
+
+    /// Reports why a lock that waited this way gave up, unless it `locked` its bits. The conflicting bits are only
+    /// observed on failure.
+    #[inline]
+    fn check<L>(
+        &self,
+        locked: bool,
+        conflicts: impl FnOnce() -> L,
+    ) -> Result<(), LockError<(), L>> {
+        if locked {
+            Ok(())
+        } else if let Wait::Until(_) = self {
+            Err(LockError::TimedOut)
+        } else {
+            Err(LockError::WouldBlock {
+                conflicting: conflicts(),
+            })
+        }
+    }
+
+    #[inline]
+    fn until(&self) -> Option<Instant> {
+        match self {
+            Wait::Until(until) => Some(*until),
+            _ => None,
+        }
+    }
+}
+
 impl<T> Drop for State<T> {
     fn drop(&mut self) {
         free_all(*self.0.get_mut());
@@ -338,63 +956,226 @@ const fn bit(index: usize) -> u32 {
     1 << (index % u32::BITS as usize)
 }
 
+/// Bumps the version and wakes the threads parked on it if one of them raised the flag. Must follow a fence.
 #[inline]
-fn wake(state: &AtomicU32, mask: u32, wake: bool) -> bool {
-    if mask == 0 {
-        false
-    } else if wake {
-        let value = state.fetch_and(!WAIT, Release);
-        if value & WAIT == WAIT {
-            state.fetch_add(2, Release);
-            system::wake(state, mask);
-        }
-        true
-    } else {
-        true
+fn wake(state: &AtomicU32, mask: u32) -> bool {
+    if state.load(Relaxed) & WAIT == 0 {
+        return true;
+    }
+    let value = state.fetch_and(!WAIT, Release);
+    if value & WAIT == WAIT {
+        state.fetch_add(2, Release);
+        system::wake(state, mask.into());
     }
+    true
 }
 
-fn lock_all<L: Lock, D: Deref<Target = L::State>>(
+fn lock_all<L: Lock<State: Word>, D: Deref<Target = L::State>>(
     states: &[D],
-    version: &AtomicU32,
+    version: &Version,
     mask: &[L],
     locks: &mut [L],
     partial: bool,
-    wait: bool,
+    wait: Wait,
 ) -> bool {
     'outer: loop {
-        let value = if wait {
-            version.load(Acquire)
-        } else {
-            u32::MAX
-        };
         for (index, pair) in states.iter().zip(mask).enumerate() {
             let (head, tail) = locks.split_at_mut(index);
             let Some((taken, _)) = tail.split_first_mut() else {
                 unreachable!()
             };
-            if pair.1.lock(pair.0, taken, partial, false) {
+            if pair.1.lock(pair.0, taken, partial, Wait::Never).is_ok() {
                 continue;
+            }
+
+            unlock_all(states, version, head, true);
+            if matches!(wait, Wait::Never) {
+                break 'outer false;
+            }
+            let conflicts = |index: usize| mask[index].is_locked(&states[index], true);
+            // The lock waits only while one of the words that it waits on still conflicts, since a word that starts
+            // conflicting afterwards does not wake it.
+            let conflicting: Vec<_> = (0..mask.len()).filter(|&index| conflicts(index)).collect();
+            let waits_on = |index| conflicting.binary_search(&index).is_ok();
+            let blocked = || conflicting.iter().any(|&index| conflicts(index));
+            match wait_words(states, version, mask, waits_on, blocked, wait) {
+                Some(true) => continue 'outer,
+                Some(false) => break 'outer false,
+                None => {}
+            }
+            // The flag is raised only after the acquired words are released since releasing them consumes it. If the
+            // conflicting word is still taken once the flag is up, its release is guaranteed to bump the version.
+            let value = version.0.fetch_or(WAIT, Acquire) | WAIT;
+            fence(SeqCst);
+            if pair.1.is_locked(pair.0, true) && !wait.park(&version.0, value, bit(index).into()) {
+                break 'outer false;
             } else {
-                unlock_all(states, version, head, true);
-                if value & WAIT == WAIT {
-                    system::wait(version, value, bit(index));
-                    continue 'outer;
-                } else if wait {
-                    version.fetch_or(WAIT, Release);
-                    continue 'outer;
-                } else {
-                    break 'outer false;
-                }
+                continue 'outer;
             }
         }
         break true;
     }
 }
 
-fn unlock_all<L: Lock, D: Deref<Target = L::State>>(
+/// Same as [`lock_all`], but keeps the words that are taken while it waits for a conflicting word, which is free of
+/// deadlocks since all the words are taken in the same order. They are only released if the lock gives up.
+fn lock_ordered_all<L: Lock<State: Word>, D: Deref<Target = L::State>>(
+    states: &[D],
+    version: &Version,
+    mask: &[L],
+    locks: &mut [L],
+    wait: Wait,
+) -> bool {
+    for (index, pair) in states.iter().zip(mask).enumerate() {
+        while pair
+            .1
+            .lock(pair.0, &mut locks[index], false, Wait::Never)
+            .is_err()
+        {
+            let conflicts = || pair.1.is_locked(pair.0, true);
+            let waited = match wait {
+                Wait::Never => false,
+                _ => match wait_words(
+                    &states[index..=index],
+                    version,
+                    &mask[index..=index],
+                    |_| true,
+                    conflicts,
+                    wait,
+                ) {
+                    Some(waited) => waited,
+                    // Same as in `lock_all`, except that the taken words are kept.
+                    None => {
+                        let value = version.0.fetch_or(WAIT, Acquire) | WAIT;
+                        fence(SeqCst);
+                        !conflicts() || wait.park(&version.0, value, bit(index).into())
+                    }
+                },
+            };
+            if !waited {
+                unlock_all(states, version, &locks[..index], true);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn lock_any_all<L: Lock<State: Word>, D: Deref<Target = L::State>>(
+    states: &[D],
+    version: &Version,
+    mask: &[L],
+    taken: &mut [L],
+    wait: Wait,
+) -> bool {
+    let mut bits = 0;
+    for (index, mask) in mask.iter().enumerate() {
+        if mask.indices().next().is_some() {
+            bits |= bit(index);
+        }
+    }
+    if bits == 0 {
+        return false;
+    }
+
+    taken.iter_mut().for_each(L::clear);
+    let mut raised = None;
+    loop {
+        for (index, pair) in states.iter().zip(mask).enumerate() {
+            if pair
+                .1
+                .lock_any(pair.0, &mut taken[index], Wait::Never)
+                .is_ok()
+            {
+                return true;
+            }
+        }
+        match raised {
+            None if matches!(wait, Wait::Never) => break false,
+            None => {
+                let some = |index: usize| mask[index].indices().next().is_some();
+                let full = || {
+                    let mut pairs = states.iter().zip(mask);
+                    pairs.all(|(state, mask)| mask.conflicts(state).indices().eq(mask.indices()))
+                };
+                match wait_words(states, version, mask, some, full, wait) {
+                    Some(true) => continue,
+                    Some(false) => break false,
+                    // The words are scanned once more after the flag is raised such that a release that happens
+                    // after the scan is guaranteed to bump the version.
+                    None => {
+                        raised = Some(version.0.fetch_or(WAIT, Acquire) | WAIT);
+                        fence(SeqCst);
+                    }
+                }
+            }
+            Some(value) => {
+                if !wait.park(&version.0, value, bits.into()) {
+                    break false;
+                }
+                raised = None;
+            }
+        }
+    }
+}
+
+fn lock_some_all<L: Lock<State: Word>, D: Deref<Target = L::State>>(
     states: &[D],
-    version: &AtomicU32,
+    version: &Version,
+    mask: &[L],
+    taken: &mut [L],
+    (min, max): (usize, usize),
+    wait: Wait,
+) -> bool {
+    let mut bits = 0;
+    for (index, mask) in mask.iter().enumerate() {
+        if mask.indices().next().is_some() {
+            bits |= bit(index);
+        }
+    }
+
+    loop {
+        let mut count = 0;
+        for (index, pair) in states.iter().zip(mask).enumerate() {
+            // Since no bit is required, the word is always locked.
+            let _ = pair
+                .1
+                .lock_some(pair.0, &mut taken[index], 0, max - count, Wait::Never);
+            count += taken[index].indices().count();
+        }
+        if count >= min {
+            break true;
+        }
+
+        unlock_all(states, version, taken, true);
+        if matches!(wait, Wait::Never) {
+            break false;
+        }
+        let free = || {
+            let mut free = 0;
+            for (state, mask) in states.iter().zip(mask) {
+                free += mask.indices().count() - mask.conflicts(state).indices().count();
+            }
+            free
+        };
+        let some = |index: usize| mask[index].indices().next().is_some();
+        match wait_words(states, version, mask, some, || free() < min, wait) {
+            Some(true) => continue,
+            Some(false) => break false,
+            None => {}
+        }
+        // Same as in `lock_all`, the flag is raised only once the taken bits are released.
+        let value = version.0.fetch_or(WAIT, Acquire) | WAIT;
+        fence(SeqCst);
+        if free() < min && !wait.park(&version.0, value, bits.into()) {
+            break false;
+        }
+    }
+}
+
+fn unlock_all<L: Lock<State: Word>, D: Deref<Target = L::State>>(
+    states: &[D],
+    version: &Version,
     masks: &[L],
     wake: bool,
 ) -> bool {
@@ -404,7 +1185,170 @@ fn unlock_all<L: Lock, D: Deref<Target = L::State>>(
             mask |= bit(index);
         }
     }
-    self::wake(version, mask, wake)
+    if mask == 0 || !wake {
+        return mask != 0;
+    }
+
+    // Pairs with the fences of `wait_words` and of the raising of the flag, such that the words are only woken and
+    // the version is only written to when a thread waits on them.
+    fence(SeqCst);
+    if version.1.load(Relaxed) > 0 {
+        // The threads of `wait_words` are only woken through the words of memory that hold the released bits.
+        for (state, mask) in states.iter().zip(masks) {
+            blocks(&**state, bits(mask)).for_each(system::wake_word);
+        }
+    }
+    self::wake(&version.0, mask)
+}
+
+/// Waits for one of the words of `states` that are selected by `wait_on` to change, provided that the lock is still
+/// `blocked` once their values are loaded. Returns whether to try again, or `None` if the words can not be waited on
+/// that way, in which case the caller waits on the version instead.
+fn wait_words<L: Lock<State: Word>, D: Deref<Target = L::State>>(
+    states: &[D],
+    version: &Version,
+    mask: &[L],
+    wait_on: impl Fn(usize) -> bool,
+    blocked: impl FnOnce() -> bool,
+    wait: Wait,
+) -> Option<bool> {
+    if matches!(wait, Wait::Never) || !system::waits_any() {
+        return None;
+    } else if wait.expired() {
+        return Some(false);
+    }
+
+    version.1.fetch_add(1, Relaxed);
+    // Pairs with the fence of `unlock_all`, such that either the release is seen here or this thread is seen by it.
+    fence(SeqCst);
+    // The values are loaded before the lock is checked such that a change in between ends the wait right away.
+    let mut words = Vec::new();
+    let loaded = (0..mask.len().min(states.len()))
+        .filter(|&index| wait_on(index))
+        .all(|index| {
+            load_words(states, index, bits(&mask[index]), &mut words)
+                && words.len() <= system::WAIT_ANY
+        });
+    if loaded && !words.is_empty() && blocked() {
+        system::wait_any(&words, wait.until());
+    }
+    version.1.fetch_sub(1, Relaxed);
+    loaded.then_some(true)
+}
+
+fn acquire<L: RwLock>(
+    mask: &L,
+    counts: &L::Counts,
+    taken: &mut L,
+    wait: Wait,
+    lock: fn(u32) -> Option<u32>,
+    unlock: fn(&AtomicU32),
+) -> Locked<L> {
+    loop {
+        let mut conflict = None;
+        taken.clear();
+        let done = mask.visit(counts, |index, count| {
+            match count.fetch_update(Acquire, Relaxed, lock) {
+                Ok(_) => {
+                    taken.add(index);
+                    true
+                }
+                Err(value) => {
+                    conflict = Some((count, value));
+                    false
+                }
+            }
+        });
+        if done {
+            break Ok(());
+        }
+
+        taken.visit(counts, |_, count| {
+            unlock(count);
+            true
+        });
+        taken.clear();
+        match conflict {
+            Some((count, value)) if wait.park(count, value, u64::MAX) => {}
+            _ => {
+                break wait.check(false, || {
+                    let mut conflicting = L::new();
+                    mask.visit(counts, |index, count| {
+                        if lock(count.load(Relaxed)).is_none() {
+                            conflicting.add(index);
+                        }
+                        true
+                    });
+                    conflicting
+                })
+            }
+        }
+    }
+}
+
+#[inline]
+fn read(count: u32) -> Option<u32> {
+    if count & WRITE == 0 {
+        Some(count + 1)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn write(count: u32) -> Option<u32> {
+    if count == 0 {
+        Some(WRITE)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn upgradable(count: u32) -> Option<u32> {
+    if count & (WRITE | UPGRADE) == 0 {
+        Some(count | UPGRADE)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn unread(count: &AtomicU32) {
+    if count.fetch_sub(1, Release) & READS == 1 {
+        system::wake(count, u64::MAX);
+    }
+}
+
+#[inline]
+fn unwrite(count: &AtomicU32) {
+    count.fetch_and(!WRITE, Release);
+    system::wake(count, u64::MAX);
+}
+
+#[inline]
+fn unupgrade(count: &AtomicU32) {
+    count.fetch_and(!UPGRADE, Release);
+    system::wake(count, u64::MAX);
+}
+
+fn visit_all<'a, L: RwLock, B: Borrow<L::Counts>>(
+    counts: &'a [B],
+    masks: &[L],
+    bits: usize,
+    mut visit: impl FnMut(usize, &'a AtomicU32) -> bool,
+) -> bool
+where
+    L::Counts: 'a,
+{
+    for (index, (counts, mask)) in counts.iter().zip(masks).enumerate() {
+        if !mask.visit(counts.borrow(), |bit, count| {
+            visit(index * bits + bit, count)
+        }) {
+            return false;
+        }
+    }
+    true
 }
 
 fn are_locked<L: Lock, D: Deref<Target = L::State>>(
@@ -1,16 +1,89 @@
+#[cfg(any(feature = "deadlock", feature = "reentrant"))]
+use crate::deadlock;
 use crate::{
+    error::LockError,
     key::{Get, Key},
-    lock::{Lock, LockAll},
+    lock::{Lock, LockAll, Locked, RwLock, Wait},
+    queue::{self, Entry, Queued},
+    system::{self, Wakers},
 };
 use std::{
     cell::UnsafeCell,
+    future::Future,
+    marker::PhantomData,
+    mem::{replace, size_of, ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut},
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering::*},
+        Arc, LockResult, PoisonError,
+    },
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
 };
 
 pub struct Multex<T: ?Sized, L: Lock = usize> {
-    pub(crate) state: L::State,
+    pub(crate) shared: Shared<L>,
     pub(crate) value: UnsafeCell<T>,
 }
+/// The part of a [`Multex`] that does not depend on its value, such that guards can refer to it.
+pub(crate) struct Shared<L: Lock> {
+    pub(crate) state: L::State,
+    /// The indices that were held by a guard that was dropped during a panic.
+    pub(crate) poison: L::State,
+    pub(crate) fairness: Fairness,
+    /// The threads that wait in the queue of the multex, which holds the ones that block for all the bits of their key
+    /// unless the multex reserves them or takes them in order, and the ones that lock with a priority.
+    pub(crate) queued: Queued,
+    /// The bits that blocked threads of a [`Fairness::Reserve`] multex have claimed ahead of new locks.
+    pub(crate) reserved: L::State,
+    pub(crate) strategy: Strategy,
+    /// The wakers of the futures that wait for the bits of the multex.
+    pub(crate) wakers: Wakers,
+    /// Whether [`Multex::close`] was called, after which no lock succeeds.
+    pub(crate) closed: AtomicBool,
+}
+/// The order in which the blocked threads of a [`Multex`] take their bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fairness {
+    /// Any thread may take the bits as soon as they are free, which favors narrow keys over wide ones.
+    #[default]
+    Barging,
+    /// The threads that block take their bits in arrival order such that a wide key is not starved by a stream of
+    /// narrow ones. Partial locks, locks that claim any or some indices and asynchronous locks still barge.
+    ///
+    /// A queued thread reserves all of its bits, including the free ones. A thread that holds some indices and then
+    /// locks others therefore waits forever if a thread that wants both was queued in between, so such threads should
+    /// extend their guard with [`Guard::lock_with`], which releases it while blocking.
+    Fifo,
+    /// The threads that block reserve all of their bits in ascending order and new locks that overlap reserved bits
+    /// wait behind them. Lighter than [`Fairness::Fifo`] since it only costs a second word of
+    /// state, but the threads that wait for the same bit are not ordered among themselves. The same locks as with
+    /// [`Fairness::Fifo`] still barge.
+    ///
+    /// As with [`Fifo`](Fairness::Fifo), a thread that holds some indices and then locks others waits forever if a
+    /// thread that wants both reserved them in between, so such threads should extend their guard with
+    /// [`Guard::lock_with`].
+    Reserve,
+}
+/// How a lock takes the bits of a [`Multex`] whose mask spans several words.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Takes all the words at once or none of them. The taken words are released as soon as one of them conflicts
+    /// and the lock starts over once the conflicting word is released.
+    #[default]
+    AllOrNothing,
+    /// Takes the words in ascending order and waits for a conflicting word while holding the earlier ones, which
+    /// wastes no work under contention but keeps words that other locks could use meanwhile. Since all the locks
+    /// take words in the same order, they can not deadlock, unless a thread that holds bits of the multex waits for
+    /// more of them. Partial locks and locks that claim any or some indices still take all the words at once.
+    ///
+    /// The fair locks wait in the order of their [`Fairness`] rather than on the words, so the strategy only applies
+    /// to a [`Fairness::Barging`] multex and the locks with a priority take all the words at once.
+    Ordered,
+}
 pub type MultexA<T, const N: usize> = Multex<T, [usize; N]>;
 pub type MultexV<T> = Multex<T, Vec<usize>>;
 pub type Multex8<T> = Multex<T, u8>;
@@ -26,16 +99,125 @@ pub type Multex64<T> = Multex<T, u64>;
 pub type Multex64A<T, const N: usize> = Multex<T, [u64; N]>;
 pub type Multex64V<T> = Multex<T, Vec<u64>>;
 
-pub struct Guard<'a, T, L: Lock>(T, Inner<'a, L>);
+/// A [`RwMultex`] allows many readers or a single writer to access each index.
+pub struct RwMultex<T: ?Sized, L: RwLock = usize> {
+    pub(crate) counts: L::Counts,
+    pub(crate) value: UnsafeCell<T>,
+}
+pub type RwMultexA<T, const N: usize> = RwMultex<T, [usize; N]>;
+pub type RwMultexV<T> = RwMultex<T, Vec<usize>>;
+pub type RwMultex8<T> = RwMultex<T, u8>;
+pub type RwMultex8A<T, const N: usize> = RwMultex<T, [u8; N]>;
+pub type RwMultex8V<T> = RwMultex<T, Vec<u8>>;
+pub type RwMultex16<T> = RwMultex<T, u16>;
+pub type RwMultex16A<T, const N: usize> = RwMultex<T, [u16; N]>;
+pub type RwMultex16V<T> = RwMultex<T, Vec<u16>>;
+pub type RwMultex32<T> = RwMultex<T, u32>;
+pub type RwMultex32A<T, const N: usize> = RwMultex<T, [u32; N]>;
+pub type RwMultex32V<T> = RwMultex<T, Vec<u32>>;
+pub type RwMultex64<T> = RwMultex<T, u64>;
+pub type RwMultex64A<T, const N: usize> = RwMultex<T, [u64; N]>;
+pub type RwMultex64V<T> = RwMultex<T, Vec<u64>>;
+
+pub struct Guard<'a, T, L: Lock>(Item<T>, Inner<'a, L>, Option<Source<'a, T>>);
 /// [`Inner`] should be kept separate from [`Guard`] such that its [`Drop`] implementation is called even if
-/// a panic occurs when the value `T` is produced.
-struct Inner<'a, L: Lock>(&'a L::State, Borrow<'a, L>);
+/// a panic occurs when the value `T` is produced. It remembers whether the thread was already panicking when the bits
+/// were taken, such that only the guards that are interrupted by a panic poison their bits.
+struct Inner<'a, L: Lock>(&'a Shared<L>, Borrow<'a, L>, bool);
+/// Holds the items of a [`Guard`] in a union, such that passing the guard by value does not assert that its references
+/// remain valid for the whole call, which they are not while the bits of the guard are released.
+struct Item<T>(MaybeUninit<T>);
+/// Produces the items of a [`Guard`] again such that they do not have to outlive the bits of the guard while they
+/// are released. Guards that were mapped, merged or split have none.
+enum Source<'a, T> {
+    /// The items of the indices at the erased address of a key, as produced by the function from the value of a
+    /// multex.
+    Key(Value, *const (), Produce<T>, PhantomData<&'a ()>),
+    /// The whole value of a multex whose bits are all held, which has no indices.
+    Whole(Value, Produce<T>),
+    /// The items of a guard that was extended with the items of another key.
+    Extended(Box<dyn FnMut(Filter) -> T + 'a>),
+}
+type Produce<T> = unsafe fn(Value, *const (), Filter) -> T;
+/// Tells whether the item of an index is produced.
+type Filter<'f> = &'f mut dyn FnMut(usize) -> bool;
+/// A pointer to the value of a [`Multex`] with its type erased, which is wide if the value is unsized.
+#[derive(Clone, Copy)]
+struct Value(MaybeUninit<[*mut (); 2]>);
+/// Takes the bits of its mask back for its [`Guard`] and produces the items of the guard again when dropped, such that
+/// a guard whose bits were released is restored even if a panic occurs meanwhile.
+struct Relock<'g, 'a, T, L: Lock>(&'g mut Guard<'a, T, L>, L);
+/// An [`OwnedGuard`] keeps its [`Multex`] alive and owns its [`Key`] such that it can be sent to other threads or
+/// stored. The locked items are produced on demand by [`OwnedGuard::get`] and [`OwnedGuard::read`].
+pub struct OwnedGuard<T: ?Sized, L: Lock, G>(Arc<Multex<T, L>>, Key<L, G>, bool);
+/// The [`OwnedGuard`] of [`Multex::lock_arc`], which holds all the bits and thus gives access to the whole value.
+pub struct ArcGuard<T: ?Sized, L: Lock>(OwnedGuard<T, L, ()>);
+
+/// A set of keys on different multexes that [`lock_many`] takes at once.
+pub trait Many<'a> {
+    type Guards;
+    fn acquire(self) -> Result<Self::Guards, LockError<Self::Guards>>;
+}
+/// A single key of a [`Many`] with the type of its multex erased.
+trait Part {
+    /// Takes the key as long as `wait` allows. A conflict is reported without the conflicting bits.
+    fn take(&mut self, wait: Wait) -> Result<(), LockError>;
+    fn untake(&mut self);
+    /// The address of the multex of the part along with the indices of its key.
+    fn key(&self) -> (*const (), Vec<usize>);
+}
+
+/// A [`MultexCondvar`] lets threads wait for a notification while only the indices of their [`Guard`] are released.
+pub struct MultexCondvar(AtomicU32);
+/// The result of adding the items `I` to a [`Guard`] of items `T`.
+type Extended<'a, T, I, L> = LockResult<Guard<'a, (T, I), L>>;
+/// A single index of a [`Multex`] of items `T` along with the guard of its item.
+type Claim<'a, T, L> = (usize, Guard<'a, <usize as Get<'a, T>>::Item, L>);
+type Claimed<'a, T, L> = LockResult<Claim<'a, T, L>>;
+/// The result of a lock that reports why it failed.
+type Acquired<'a, T, L> = Result<Guard<'a, T, L>, LockError<Guard<'a, T, L>, L>>;
+type OwnedAcquired<T, L, G> = Result<OwnedGuard<T, L, G>, LockError<OwnedGuard<T, L, G>, L>>;
+/// The guard `G` that could not be extended, if it did not release its bits, along with the reason.
+type Refused<G, L> = (Option<G>, LockError<(), L>);
 enum Borrow<'a, T> {
     Own(T),
     Mut(&'a mut T),
 }
 
+pub struct ReadGuard<'a, T, L: RwLock>(T, RwInner<'a, L>);
+pub struct WriteGuard<'a, T, L: RwLock>(T, RwInner<'a, L>);
+pub struct UpgradableGuard<'a, T: ?Sized, G: Get<'a, T>, L: RwLock>(G::Read, Upgrade<'a, T, G, L>);
+pub struct UpgradedGuard<'a, T: ?Sized, G: Get<'a, T>, L: RwLock>(G::Item, Upgrade<'a, T, G, L>);
+/// An [`Upgrade`] keeps the value and the indices of its key around such that its items can be produced again
+/// when switching between upgradable and write access.
+struct Upgrade<'a, T: ?Sized, G, L: RwLock>(&'a UnsafeCell<T>, &'a G, RwInner<'a, L>);
+/// Same as [`Inner`], but releases the counters of a [`RwMultex`] with the access `mode` it acquired them with.
+struct RwInner<'a, L: RwLock>(&'a L::Counts, Borrow<'a, L>, Mode);
+/// The [`Future`] of [`Multex::lock_async`]. Polling it never blocks and dropping it leaves no bit taken.
+pub struct LockFuture<'a, T: ?Sized, L: Lock>(Option<&'a Multex<T, L>>, Option<usize>);
+/// The [`Future`] of [`Multex::lock_with_async`]. Polling it never blocks and dropping it leaves no bit taken.
+pub struct LockWithFuture<'a, T: ?Sized, G, L: Lock>(
+    &'a Multex<T, L>,
+    Option<&'a mut Key<L, G>>,
+    bool,
+    Option<usize>,
+);
+/// Same as [`LockFuture`], but resolves to the result of [`Multex::acquire_async`].
+pub struct AcquireFuture<'a, T: ?Sized, L: Lock>(LockFuture<'a, T, L>);
+/// Same as [`LockWithFuture`], but resolves to the result of [`Multex::acquire_with_async`].
+pub struct AcquireWithFuture<'a, T: ?Sized, G, L: Lock>(LockWithFuture<'a, T, G, L>);
+#[derive(Clone, Copy)]
+enum Mode {
+    Read,
+    Write,
+    Upgrade,
+}
+
 unsafe impl<T: Sync, L: Lock> Sync for Multex<T, L> {}
+// A source only produces the items `T` of its guard, which it is sent and shared along with.
+unsafe impl<T: Send> Send for Source<'_, T> {}
+unsafe impl<T: Sync> Sync for Source<'_, T> {}
+unsafe impl<T: Send + Sync, L: RwLock> Sync for RwMultex<T, L> {}
 
 impl<T> Deref for Borrow<'_, T> {
     type Target = T;
@@ -62,7 +244,71 @@ impl<T> DerefMut for Borrow<'_, T> {
 impl<'a, T, L: Lock> Guard<'a, T, L> {
     #[inline]
     pub fn map<U, F: FnOnce(T) -> U>(guard: Self, map: F) -> Guard<'a, U, L> {
-        Guard(map(guard.0), guard.1)
+        Guard(Item::new(map(guard.0.into_inner())), guard.1, None)
+    }
+
+    /// Adds the indices of `key` to the ones already held by `guard`. If they can not be taken immediately, all the
+    /// bits of the guard are released and the union of both masks is taken at once, such that waiting while holding
+    /// bits can never deadlock. The items of the guard are dropped meanwhile and produced again from its key.
+    ///
+    /// # Panics
+    /// Panics if `guard` was not produced by `multex`, if `key` contains an index that `guard` already holds or if
+    /// `guard` can not produce its items again because it was mapped, merged or split.
+    #[inline]
+    pub fn lock_with<U: ?Sized, G: Get<'a, U> + Clone + 'a>(
+        guard: Self,
+        multex: &'a Multex<U, L>,
+        key: &mut Key<L, G>,
+        partial: bool,
+    ) -> Extended<'a, T, G::Item, L>
+    where
+        T: 'a,
+    {
+        forever(Self::acquire_with(guard, multex, key, partial))
+    }
+
+    /// Same as [`Guard::lock_with`], but reports why the indices of `key` could not be taken. The `guard` is dropped
+    /// along with the error, since it may have released its bits while waiting.
+    ///
+    /// # Panics
+    /// Panics in the same cases as [`Guard::lock_with`].
+    #[inline]
+    pub fn acquire_with<U: ?Sized, G: Get<'a, U> + Clone + 'a>(
+        guard: Self,
+        multex: &'a Multex<U, L>,
+        key: &mut Key<L, G>,
+        partial: bool,
+    ) -> Acquired<'a, (T, G::Item), L>
+    where
+        T: 'a,
+    {
+        assert!(
+            guard.2.is_some(),
+            "the guard must be able to produce its items again"
+        );
+        match Self::extend_with(guard, multex, key, partial, true) {
+            Ok(guard) => checked(guard),
+            Err((_, error)) => Err(failed(error)),
+        }
+    }
+
+    /// Same as [`Guard::lock_with`], but gives back the unchanged `guard` if the indices of `key` can not be taken
+    /// immediately.
+    #[inline]
+    pub fn try_lock_with<U: ?Sized, G: Get<'a, U> + Clone + 'a>(
+        guard: Self,
+        multex: &'a Multex<U, L>,
+        key: &mut Key<L, G>,
+        partial: bool,
+    ) -> Result<Extended<'a, T, G::Item, L>, Self>
+    where
+        T: 'a,
+    {
+        match Self::extend_with(guard, multex, key, partial, false) {
+            Ok(guard) => Ok(guard),
+            Err((Some(guard), _)) => Err(guard),
+            Err((None, _)) => unreachable!(),
+        }
     }
 
     #[inline]
@@ -71,6 +317,362 @@ impl<'a, T, L: Lock> Guard<'a, T, L> {
     }
 }
 
+impl<'a, I: IntoIterator, L: Lock> Guard<'a, I, L> {
+    /// Splits `guard` into one guard per item, each of which releases only the index of its item. Items that were not
+    /// taken get a guard that holds no index.
+    ///
+    /// # Panics
+    /// Panics if the guard was mapped, merged or split, or if its key does not have exactly one index per item.
+    pub fn split(guard: Self) -> Vec<Guard<'a, I::Item, L>> {
+        let Guard(items, mut inner, source) = guard;
+        let Some(indices) = source.map(|mut source| source.indices()) else {
+            panic!("the indices of a mapped, merged or split guard are unknown")
+        };
+        let items = items.into_inner().into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            indices.len(),
+            items.len(),
+            "the key must have exactly one index per item"
+        );
+
+        let mut masks = Vec::with_capacity(items.len());
+        for index in indices {
+            let mut mask = L::new();
+            if inner.1.has(index) {
+                mask.add(index);
+            }
+            masks.push(mask);
+        }
+        // The bits now belong to the new guards, so the original one must not release them.
+        inner.1.clear();
+        items
+            .into_iter()
+            .zip(masks)
+            .map(|(item, mask)| {
+                let part = Inner(inner.0, Borrow::Own(mask), inner.2);
+                Guard(Item::new(item), part, None)
+            })
+            .collect()
+    }
+}
+
+impl<'a, T, L: Lock> Guard<'a, T, L> {
+    /// Releases the indices of `release` right away and turns their items into `None`, while `guard` keeps the other
+    /// ones.
+    ///
+    /// # Panics
+    /// Panics if the guard was mapped, merged or split, or if its key does not have exactly one index per item.
+    #[inline]
+    pub fn release<X, R: IntoIterator<Item = usize>>(guard: &mut Self, release: R)
+    where
+        T: AsMut<[Option<X>]>,
+    {
+        let mut mask = L::new();
+        for index in release {
+            mask.add(index);
+        }
+        Self::retain(guard, |index, _| !mask.has(index));
+    }
+
+    /// Same as [`Guard::release`], but releases the indices for which `retain` returns `false`.
+    pub fn retain<X, F: FnMut(usize, &mut X) -> bool>(guard: &mut Self, mut retain: F)
+    where
+        T: AsMut<[Option<X>]>,
+    {
+        let Guard(items, inner, source) = guard;
+        let Some(indices) = source.as_mut().map(Source::indices) else {
+            panic!("the indices of a mapped, merged or split guard are unknown")
+        };
+        let items = items.as_mut();
+        assert_eq!(
+            indices.len(),
+            items.len(),
+            "the key must have exactly one index per item"
+        );
+
+        let mut released = L::new();
+        for (item, index) in items.iter_mut().zip(indices) {
+            if let Some(value) = item {
+                if inner.1.has(index) && !retain(index, value) {
+                    *item = None;
+                    inner.1.remove(index);
+                    released.add(index);
+                }
+            }
+        }
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::release(&inner.0.state, &released);
+        unlock(inner.0, &released);
+    }
+
+    /// Releases the bits of `guard` while `unlocked` runs and takes exactly the same bits back before returning,
+    /// waiting for them if necessary. The items of the guard are produced again once the bits are taken back.
+    ///
+    /// # Panics
+    /// Panics if the guard was mapped, merged or split, since its items cannot be produced again.
+    pub fn unlocked<U, F: FnOnce() -> U>(guard: &mut Self, unlocked: F) -> U {
+        Self::unlocked_by(guard, unlock, unlocked)
+    }
+
+    /// Releases the bits of `guard` and hands them over to the threads that block for them, which take them before
+    /// the current thread can take them back. The bits that no thread waits for are released as usual. Only the
+    /// threads that wait in the queue of the multex are handed bits, see [`Multex::queued`].
+    #[inline]
+    pub fn unlock_fair(guard: Self) {
+        let Guard(items, mut inner, _) = guard;
+        drop(items);
+        release(inner.0, &mut inner.1, inner.2, hand_off);
+    }
+
+    /// Same as [`Guard::unlock_fair`], but takes exactly the same bits back before returning, waiting behind the
+    /// threads that were handed bits if necessary. The items of the guard are produced again once the bits are taken
+    /// back.
+    ///
+    /// # Panics
+    /// Panics if the guard was mapped, merged or split, since its items cannot be produced again.
+    #[inline]
+    pub fn bump(guard: &mut Self) {
+        Self::unlocked_by(guard, hand_off, || ());
+    }
+
+    fn unlocked_by<U, F: FnOnce() -> U>(
+        guard: &mut Self,
+        unlock: fn(&Shared<L>, &L),
+        unlocked: F,
+    ) -> U {
+        assert!(
+            guard.2.is_some(),
+            "the items of a mapped, merged or split guard cannot be produced again"
+        );
+        let mask = replace(&mut *guard.1 .1, L::new());
+        // The items are written again by the relock before the guard can be used or dropped.
+        unsafe { guard.0 .0.assume_init_drop() };
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::release(&guard.1 .0.state, &mask);
+        unlock(guard.1 .0, &mask);
+        let relock = Relock(guard, mask);
+        let value = unlocked();
+        drop(relock);
+        value
+    }
+
+    /// Combines two guards of the same [`Multex`] into one that holds the union of their indices.
+    ///
+    /// # Panics
+    /// Panics if the guards do not come from the same multex.
+    #[inline]
+    pub fn merge<U>(left: Self, right: Guard<'a, U, L>) -> Guard<'a, (T, U), L> {
+        let (Guard(left, mut inner, _), Guard(right, mut other, _)) = (left, right);
+        assert!(
+            ptr::eq(inner.0, other.0),
+            "the guards must come from the same multex"
+        );
+        inner.1.merge(&other.1);
+        inner.2 &= other.2;
+        other.1.clear();
+        Guard(
+            Item::new((left.into_inner(), right.into_inner())),
+            inner,
+            None,
+        )
+    }
+
+    fn extend_with<U: ?Sized, G: Get<'a, U> + Clone + 'a>(
+        guard: Self,
+        multex: &'a Multex<U, L>,
+        key: &mut Key<L, G>,
+        partial: bool,
+        wait: bool,
+    ) -> Result<Extended<'a, T, G::Item, L>, Refused<Self, L>>
+    where
+        T: 'a,
+    {
+        let Guard(item, mut inner, mut source) = guard;
+        let mut item = item.into_inner();
+        assert!(
+            ptr::eq(inner.0, &multex.shared),
+            "the guard must come from the same multex"
+        );
+        assert!(
+            !key.mask.overlaps(&inner.1),
+            "the key must not contain indices that are already held by the guard"
+        );
+
+        // The guard is handed back along with the error, unless it released its bits to wait.
+        let locked = if inner.0.closed.load(SeqCst) {
+            Err(LockError::Closed)
+        } else {
+            let locked = key
+                .mask
+                .lock(&inner.0.state, &mut key.taken, partial, Wait::Never);
+            opened(inner.0, &mut key.taken, locked)
+        };
+        if let Err(error) = locked {
+            let (true, Some(items), LockError::WouldBlock { .. }) = (wait, &mut source, &error)
+            else {
+                return Err((Some(Guard(Item::new(item), inner, source)), error));
+            };
+
+            // The items of the guard must not outlive its bits, so they are produced again once the bits are taken
+            // back. The mask is moved out meanwhile such that a panic does not release bits that are not held.
+            drop(item);
+            let held = replace(&mut *inner.1, L::new());
+            let mut mask = L::new();
+            mask.merge(&held);
+            mask.merge(&key.mask);
+            #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+            deadlock::release(&inner.0.state, &held);
+            unlock(inner.0, &held);
+            let mut taken = L::new();
+            if let Err(error) = lock(inner.0, &mask, &mut taken, false, Wait::Forever) {
+                return Err((None, error));
+            }
+            #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+            deadlock::hold(&inner.0.state, &held);
+            item = unsafe { items.get(&mut |index| held.has(index)) };
+            *inner.1 = held;
+            key.taken.clear();
+            key.taken.merge(&key.mask);
+        }
+
+        let value = Value::new(multex.value.get());
+        let items = unsafe { key.indices.get(value.get(), |index| key.taken.has(index)) };
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&inner.0.state, &key.taken);
+        let poisoned = key.taken.is_locked(&multex.shared.poison, true);
+        inner.1.merge(&key.taken);
+        key.taken.clear();
+        // The whole value ignores the filter of its source, so its items could not be listed by index.
+        let source = source.filter(|source| !matches!(source, Source::Whole(..)));
+        let source = source.map(|mut source| {
+            let indices = key.indices.clone();
+            Source::Extended(Box::new(move |filter| {
+                let item = unsafe { source.get(filter) };
+                (item, unsafe { indices.get(value.get(), filter) })
+            }))
+        });
+        Ok(poison(
+            Guard(Item::new((item, items)), inner, source),
+            poisoned,
+        ))
+    }
+}
+
+impl<T> Item<T> {
+    #[inline]
+    fn new(item: T) -> Self {
+        Self(MaybeUninit::new(item))
+    }
+
+    #[inline]
+    fn into_inner(self) -> T {
+        let item = ManuallyDrop::new(self);
+        unsafe { item.0.assume_init_read() }
+    }
+}
+
+impl<T> Deref for Item<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.0.assume_init_ref() }
+    }
+}
+
+impl<T> DerefMut for Item<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.0.assume_init_mut() }
+    }
+}
+
+impl<T> Drop for Item<T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.0.assume_init_drop() }
+    }
+}
+
+impl<T> Source<'_, T> {
+    /// Produces the items whose indices pass `filter`.
+    ///
+    /// # Safety
+    /// The bits of the indices that pass `filter` must be held by the guard of the source and none of the items that
+    /// were produced before may still be alive.
+    #[inline]
+    unsafe fn get(&mut self, filter: Filter) -> T {
+        match self {
+            Source::Key(value, indices, items, _) => items(*value, *indices, filter),
+            Source::Whole(value, items) => items(*value, ptr::null(), filter),
+            Source::Extended(items) => items(filter),
+        }
+    }
+
+    /// Lists the indices of the source in the order of its items, without producing any of them.
+    ///
+    /// # Panics
+    /// Panics if the source is the whole value of a multex.
+    fn indices(&mut self) -> Vec<usize> {
+        assert!(
+            !matches!(self, Source::Whole(..)),
+            "the whole value of a multex has no indices"
+        );
+        let mut indices = Vec::new();
+        // Every index is rejected, so no item is produced that could alias the items of the guard.
+        drop(unsafe {
+            self.get(&mut |index| {
+                indices.push(index);
+                false
+            })
+        });
+        indices
+    }
+}
+
+impl Value {
+    #[inline]
+    fn new<T: ?Sized>(value: *mut T) -> Self {
+        assert!(size_of::<*mut T>() <= size_of::<Self>());
+        let mut raw = MaybeUninit::<[*mut (); 2]>::uninit();
+        unsafe { raw.as_mut_ptr().cast::<*mut T>().write(value) };
+        Self(raw)
+    }
+
+    /// # Safety
+    /// `T` must be the type that the value was created with.
+    #[inline]
+    unsafe fn get<T: ?Sized>(self) -> *mut T {
+        self.0.as_ptr().cast::<*mut T>().read()
+    }
+}
+
+/// Produces the items of the `indices` of a key of type `G`.
+unsafe fn keyed<'a, T: ?Sized, G: Get<'a, T>>(
+    value: Value,
+    indices: *const (),
+    filter: Filter,
+) -> G::Item {
+    (*indices.cast::<G>()).get(value.get(), filter)
+}
+
+/// Produces the whole value of a multex, whose bits are all held.
+unsafe fn whole<'a, T: ?Sized + 'a>(value: Value, _: *const (), _: Filter) -> &'a mut T {
+    &mut *value.get::<T>()
+}
+
+/// Produces the item of a single index, which is stored in place of the address of a key.
+unsafe fn claimed<'a, T: ?Sized>(
+    value: Value,
+    index: *const (),
+    filter: Filter,
+) -> <usize as Get<'a, T>>::Item
+where
+    usize: Get<'a, T>,
+{
+    (index as usize).get(value.get(), filter)
+}
+
 impl<T, L: Lock> Deref for Guard<'_, T, L> {
     type Target = T;
 
@@ -101,148 +703,1727 @@ impl<T, L: Lock> AsMut<T> for Guard<'_, T, L> {
     }
 }
 
+impl<T, L: Lock> Drop for Relock<'_, '_, T, L> {
+    fn drop(&mut self) {
+        let Relock(Guard(item, inner, source), mask) = self;
+        let mut taken = L::new();
+        if lock(inner.0, mask, &mut taken, false, Wait::Forever).is_err() {
+            unreachable!()
+        }
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&inner.0.state, mask);
+        *inner.1 = replace(mask, L::new());
+        let Some(source) = source else { unreachable!() };
+        // The bits are held again and the previous items were dropped when they were released.
+        item.0
+            .write(unsafe { source.get(&mut |index| inner.1.has(index)) });
+    }
+}
+
 impl<L: Lock> Drop for Inner<'_, L> {
     #[inline]
     fn drop(&mut self) {
-        self.1.unlock(self.0, true);
-        self.1.clear();
+        release(self.0, &mut *self.1, self.2, unlock);
     }
 }
 
-impl<T, L: Lock> Multex<T, L> {
+impl<T: ?Sized, L: Lock, G> OwnedGuard<T, L, G> {
     #[inline]
-    pub const fn new(values: T) -> Self {
-        Self {
-            state: L::NEW,
-            value: UnsafeCell::new(values),
+    pub fn get<'a>(&'a mut self) -> G::Item
+    where
+        G: Get<'a, T>,
+    {
+        let OwnedGuard(multex, key, _) = self;
+        unsafe {
+            key.indices
+                .get(multex.value.get(), |index| key.taken.has(index))
         }
     }
 
     #[inline]
-    pub fn into_inner(self) -> T {
-        self.value.into_inner()
+    pub fn read<'a>(&'a self) -> G::Read
+    where
+        G: Get<'a, T>,
+    {
+        let OwnedGuard(multex, key, _) = self;
+        unsafe {
+            key.indices
+                .read(multex.value.get(), |index| key.taken.has(index))
+        }
+    }
+
+    #[inline]
+    pub fn mask(&self) -> &L {
+        let OwnedGuard(_, key, _) = self;
+        &key.taken
+    }
+
+    #[inline]
+    pub fn multex(&self) -> &Arc<Multex<T, L>> {
+        &self.0
+    }
+
+    /// Releases the bits of the guard and gives back its key.
+    #[inline]
+    pub fn into_key(guard: Self) -> Key<L, G> {
+        let mut guard = ManuallyDrop::new(guard);
+        let OwnedGuard(multex, key, panicking) = &mut *guard;
+        release(&multex.shared, &mut key.taken, *panicking, unlock);
+        // The fields are moved out exactly once and the guard itself is never dropped.
+        let (multex, key) = unsafe { (ptr::read(&guard.0), ptr::read(&guard.1)) };
+        drop(multex);
+        key
     }
 }
 
-impl<T: ?Sized, L: LockAll> Multex<T, L> {
+impl<T: ?Sized, L: Lock> ArcGuard<T, L> {
     #[inline]
-    pub fn lock(&self) -> Guard<'_, &mut T, L> {
-        let mut mask = L::ALL;
-        if L::ALL.lock(&self.state, &mut mask, false, true) {
-            unsafe { self.guard(mask) }
-        } else {
-            unreachable!()
-        }
+    pub fn multex(&self) -> &Arc<Multex<T, L>> {
+        self.0.multex()
     }
+}
+
+impl<T: ?Sized, L: Lock> Deref for ArcGuard<T, L> {
+    type Target = T;
 
     #[inline]
-    pub fn try_lock(&self) -> Option<Guard<&mut T, L>> {
-        let mut mask = L::ALL;
-        if L::ALL.lock(&self.state, &mut mask, false, false) {
-            Some(unsafe { self.guard(mask) })
-        } else {
-            None
-        }
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.0 .0.value.get() }
     }
+}
 
-    /// Forcefully unlocks all the bits. A normal usage of a [`Multex`] normally doesn't require to unlock manually
-    /// since the [`Guard`] already does it automatically. This method is mainly meant to be used when [`std::mem::forget(guard)`] is
-    /// used.
-    ///
-    /// # Safety
-    /// This method is marked as `unsafe` because it may unlock bits that are still locked by a [`Guard`]. A wrong usage of unlock will
-    /// allow multiple concurrent mutable references to exist, thus causing undefined behavior.
+impl<T: ?Sized, L: Lock> DerefMut for ArcGuard<T, L> {
     #[inline]
-    pub unsafe fn unlock(&self) {
-        L::ALL.unlock(&self.state, true);
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.0 .0.value.get() }
     }
+}
 
+impl<T: ?Sized, L: Lock, G> Drop for OwnedGuard<T, L, G> {
     #[inline]
-    pub fn is_locked(&self, partial: bool) -> bool {
-        L::ALL.is_locked(&self.state, partial)
+    fn drop(&mut self) {
+        let OwnedGuard(multex, key, panicking) = self;
+        release(&multex.shared, &mut key.taken, *panicking, unlock);
+    }
+}
+
+impl<'a, T, L: RwLock> ReadGuard<'a, T, L> {
+    #[inline]
+    pub fn map<U, F: FnOnce(T) -> U>(guard: Self, map: F) -> ReadGuard<'a, U, L> {
+        ReadGuard(map(guard.0), guard.1)
     }
 
     #[inline]
-    unsafe fn guard(&self, mask: L) -> Guard<&mut T, L> {
-        let inner = Inner(&self.state, Borrow::Own(mask));
-        Guard(unsafe { &mut *self.value.get() }, inner)
+    pub fn mask(&self) -> &L {
+        &self.1 .1
     }
 }
 
-impl<T: ?Sized, L: Lock> Multex<T, L> {
+impl<T, L: RwLock> Deref for ReadGuard<'_, T, L> {
+    type Target = T;
+
     #[inline]
-    pub const fn as_ptr(&self) -> *const T {
-        self.value.get()
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
+impl<T, L: RwLock> AsRef<T> for ReadGuard<'_, T, L> {
     #[inline]
-    pub const fn as_mut_ptr(&self) -> *mut T {
-        self.value.get()
+    fn as_ref(&self) -> &T {
+        &self.0
     }
+}
 
+impl<'a, T, L: RwLock> WriteGuard<'a, T, L> {
     #[inline]
-    pub fn lock_with<'a, G: Get<'a, T>>(
-        &'a self,
-        key: &'a mut Key<L, G>,
-        partial: bool,
-    ) -> Guard<'a, G::Item, L> {
-        if key.mask.lock(&self.state, &mut key.taken, partial, true) {
-            unsafe { self.guard_with(key) }
-        } else {
-            unreachable!()
-        }
+    pub fn map<U, F: FnOnce(T) -> U>(guard: Self, map: F) -> WriteGuard<'a, U, L> {
+        WriteGuard(map(guard.0), guard.1)
     }
 
     #[inline]
-    pub fn try_lock_with<'a, G: Get<'a, T>>(
-        &'a self,
-        key: &'a mut Key<L, G>,
-        partial: bool,
-    ) -> Option<Guard<'a, G::Item, L>> {
-        if key.mask.lock(&self.state, &mut key.taken, partial, false) {
-            Some(unsafe { self.guard_with(key) })
-        } else {
-            None
-        }
+    pub fn mask(&self) -> &L {
+        &self.1 .1
     }
+}
+
+impl<T, L: RwLock> Deref for WriteGuard<'_, T, L> {
+    type Target = T;
 
-    /// Forcefully unlocks the bits contained in the provided `mask`. A normal usage of a [`Multex`] normally doesn't require to unlock
-    /// manually since the [`Guard`] already does it automatically. This method is mainly meant to be used when
-    /// [`std::mem::forget(guard)`] is used.
-    ///
-    /// # Safety
-    /// This method is marked as `unsafe` because it may unlock bits that are still locked by a [`Guard`]. A wrong usage of unlock will
-    /// allow multiple concurrent mutable references to exist, thus causing undefined behavior.
     #[inline]
-    pub unsafe fn unlock_with(&self, mask: &L) {
-        mask.unlock(&self.state, true);
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
+impl<T, L: RwLock> DerefMut for WriteGuard<'_, T, L> {
     #[inline]
-    pub fn is_locked_with<'a, G: Get<'a, T>>(&self, key: &Key<L, G>, partial: bool) -> bool {
-        key.mask.is_locked(&self.state, partial)
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
+}
 
+impl<T, L: RwLock> AsRef<T> for WriteGuard<'_, T, L> {
     #[inline]
-    pub fn get_mut(&mut self) -> &mut T {
-        self.value.get_mut()
+    fn as_ref(&self) -> &T {
+        &self.0
     }
+}
 
+impl<T, L: RwLock> AsMut<T> for WriteGuard<'_, T, L> {
     #[inline]
-    pub fn get_mut_with<'a, G: Get<'a, T>>(&mut self, key: &mut Key<L, G>) -> G::Item {
-        key.taken.clear();
-        let value = self.value.get_mut();
-        unsafe { key.indices.get(value, |index| key.taken.add(index)) }
+    fn as_mut(&mut self) -> &mut T {
+        &mut self.0
     }
+}
 
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> UpgradableGuard<'a, T, G, L> {
+    /// Waits for the other readers of the guarded indices to leave and then grants write access to the same
+    /// indices. Other upgradable guards or writers can not take these indices in the meantime.
     #[inline]
-    unsafe fn guard_with<'a, G: Get<'a, T>>(
-        &'a self,
-        key: &'a mut Key<L, G>,
-    ) -> Guard<'a, G::Item, L> {
-        let item = key
-            .indices
-            .get(self.value.get(), |index| key.taken.has(index));
-        Guard(item, Inner(&self.state, Borrow::Mut(&mut key.taken)))
+    pub fn upgrade(guard: Self) -> UpgradedGuard<'a, T, G, L> {
+        let UpgradableGuard(_, mut upgrade) = guard;
+        upgrade.2 .1.upgrade(upgrade.2 .0);
+        upgrade.2 .2 = Mode::Write;
+        unsafe { upgrade.write() }
+    }
+
+    #[inline]
+    pub fn try_upgrade(guard: Self) -> Result<UpgradedGuard<'a, T, G, L>, Self> {
+        if guard.1 .2 .1.try_upgrade(guard.1 .2 .0) {
+            let UpgradableGuard(_, mut upgrade) = guard;
+            upgrade.2 .2 = Mode::Write;
+            Ok(unsafe { upgrade.write() })
+        } else {
+            Err(guard)
+        }
+    }
+
+    #[inline]
+    pub fn mask(&self) -> &L {
+        &self.1 .2 .1
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> Deref for UpgradableGuard<'a, T, G, L> {
+    type Target = G::Read;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> AsRef<G::Read> for UpgradableGuard<'a, T, G, L> {
+    #[inline]
+    fn as_ref(&self) -> &G::Read {
+        &self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> UpgradedGuard<'a, T, G, L> {
+    #[inline]
+    pub fn downgrade(guard: Self) -> UpgradableGuard<'a, T, G, L> {
+        let UpgradedGuard(_, mut upgrade) = guard;
+        upgrade.2 .1.downgrade(upgrade.2 .0);
+        upgrade.2 .2 = Mode::Upgrade;
+        unsafe { upgrade.read() }
+    }
+
+    #[inline]
+    pub fn mask(&self) -> &L {
+        &self.1 .2 .1
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> Deref for UpgradedGuard<'a, T, G, L> {
+    type Target = G::Item;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> DerefMut for UpgradedGuard<'a, T, G, L> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> AsRef<G::Item> for UpgradedGuard<'a, T, G, L> {
+    #[inline]
+    fn as_ref(&self) -> &G::Item {
+        &self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> AsMut<G::Item> for UpgradedGuard<'a, T, G, L> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut G::Item {
+        &mut self.0
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: RwLock> Upgrade<'a, T, G, L> {
+    #[inline]
+    unsafe fn read(self) -> UpgradableGuard<'a, T, G, L> {
+        let Upgrade(value, indices, inner) = &self;
+        let item = indices.read(value.get(), |index| inner.1.has(index));
+        UpgradableGuard(item, self)
+    }
+
+    #[inline]
+    unsafe fn write(self) -> UpgradedGuard<'a, T, G, L> {
+        let Upgrade(value, indices, inner) = &self;
+        let item = indices.get(value.get(), |index| inner.1.has(index));
+        UpgradedGuard(item, self)
+    }
+}
+
+impl<L: RwLock> Drop for RwInner<'_, L> {
+    #[inline]
+    fn drop(&mut self) {
+        match self.2 {
+            Mode::Read => self.1.unlock_read(self.0),
+            Mode::Write => self.1.unlock_write(self.0),
+            Mode::Upgrade => self.1.unlock_upgradable(self.0),
+        }
+        self.1.clear();
+    }
+}
+
+impl<T, L: Lock> Multex<T, L> {
+    #[inline]
+    pub const fn new(values: T) -> Self {
+        Self::with_fairness(values, Fairness::Barging)
+    }
+
+    #[inline]
+    pub const fn with_fairness(values: T, fairness: Fairness) -> Self {
+        Self::with_policy(values, fairness, Strategy::AllOrNothing)
+    }
+
+    #[inline]
+    pub const fn with_strategy(values: T, strategy: Strategy) -> Self {
+        Self::with_policy(values, Fairness::Barging, strategy)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the [`Strategy::Ordered`] strategy is combined with a fairness other than [`Fairness::Barging`].
+    #[inline]
+    pub const fn with_policy(values: T, fairness: Fairness, strategy: Strategy) -> Self {
+        assert!(
+            matches!(fairness, Fairness::Barging) || !matches!(strategy, Strategy::Ordered),
+            "the ordered strategy only applies to a barging multex"
+        );
+        Self {
+            shared: Shared {
+                state: L::NEW,
+                poison: L::NEW,
+                fairness,
+                queued: Queued::new(matches!(fairness, Fairness::Fifo)),
+                reserved: L::NEW,
+                strategy,
+                wakers: Wakers::new(),
+                closed: AtomicBool::new(false),
+            },
+            value: UnsafeCell::new(values),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: LockAll> Multex<T, L> {
+    #[inline]
+    pub fn lock(&self) -> LockResult<Guard<'_, &mut T, L>> {
+        forever(self.acquire(Wait::Forever))
+    }
+
+    #[inline]
+    pub fn try_lock(&self) -> Option<LockResult<Guard<'_, &mut T, L>>> {
+        self.lock_wait(Wait::Never)
+    }
+
+    #[inline]
+    pub fn lock_for(&self, timeout: Duration) -> Option<LockResult<Guard<'_, &mut T, L>>> {
+        self.lock_wait(Wait::after(timeout))
+    }
+
+    #[inline]
+    pub fn lock_until(&self, until: Instant) -> Option<LockResult<Guard<'_, &mut T, L>>> {
+        self.lock_wait(Wait::Until(until))
+    }
+
+    /// Same as [`Multex::lock`], but the returned guard owns a clone of the [`Arc`] instead of borrowing the multex.
+    #[inline]
+    pub fn lock_arc(self: &Arc<Self>) -> LockResult<ArcGuard<T, L>> {
+        forever(self.acquire_arc(Wait::Forever))
+    }
+
+    /// Same as [`Multex::lock_arc`], but waits as long as `wait` allows and reports why the indices could not be
+    /// taken.
+    #[inline]
+    pub fn acquire_arc(
+        self: &Arc<Self>,
+        wait: Wait,
+    ) -> Result<ArcGuard<T, L>, LockError<ArcGuard<T, L>, L>> {
+        let mut key = Key {
+            mask: L::ALL,
+            taken: L::new(),
+            indices: (),
+        };
+        lock(&self.shared, &key.mask, &mut key.taken, false, wait).map_err(failed)?;
+        match unsafe { self.owned_guard(key) } {
+            Ok(guard) => Ok(ArcGuard(guard)),
+            Err(error) => Err(LockError::Poisoned(ArcGuard(error.into_inner()))),
+        }
+    }
+
+    /// Same as [`Multex::lock`], but the returned future is woken when the bits are released instead of blocking
+    /// the thread.
+    #[inline]
+    pub fn lock_async(&self) -> LockFuture<'_, T, L> {
+        LockFuture(Some(self), None)
+    }
+
+    /// Same as [`Multex::lock_async`], but the returned future reports why the bits could not be taken like
+    /// [`Multex::acquire`] does.
+    #[inline]
+    pub fn acquire_async(&self) -> AcquireFuture<'_, T, L> {
+        AcquireFuture(self.lock_async())
+    }
+
+    /// Forcefully unlocks all the bits. A normal usage of a [`Multex`] normally doesn't require to unlock manually
+    /// since the [`Guard`] already does it automatically. This method is mainly meant to be used when [`std::mem::forget(guard)`] is
+    /// used.
+    ///
+    /// # Safety
+    /// This method is marked as `unsafe` because it may unlock bits that are still locked by a [`Guard`]. A wrong usage of unlock will
+    /// allow multiple concurrent mutable references to exist, thus causing undefined behavior.
+    #[inline]
+    pub unsafe fn unlock(&self) {
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::release(&self.shared.state, &L::ALL);
+        unlock(&self.shared, &L::ALL);
+    }
+
+    #[inline]
+    pub fn is_locked(&self, partial: bool) -> bool {
+        L::ALL.is_locked(&self.shared.state, partial)
+    }
+
+    #[inline]
+    pub fn is_poisoned(&self, partial: bool) -> bool {
+        L::ALL.is_locked(&self.shared.poison, partial)
+    }
+
+    #[inline]
+    pub fn clear_poison(&self) {
+        L::ALL.unlock(&self.shared.poison, false);
+    }
+
+    /// Same as [`Multex::lock`], but waits as long as `wait` allows and reports why the indices could not be taken.
+    /// A poisoned guard is handed back through [`LockError::Poisoned`].
+    #[inline]
+    #[allow(clippy::mut_from_ref)] // The reference is only reachable through the guard that holds all the bits.
+    pub fn acquire(&self, wait: Wait) -> Acquired<'_, &mut T, L> {
+        let mut mask = L::ALL;
+        lock(&self.shared, &L::ALL, &mut mask, false, wait).map_err(failed)?;
+        checked(unsafe { self.guard(mask) })
+    }
+
+    #[inline]
+    fn lock_wait(&self, wait: Wait) -> Option<LockResult<Guard<'_, &mut T, L>>> {
+        match self.acquire(wait) {
+            Ok(guard) => Some(Ok(guard)),
+            Err(LockError::Poisoned(guard)) => Some(Err(PoisonError::new(guard))),
+            Err(_) => None,
+        }
+    }
+
+    /// # Safety
+    /// The bits of `mask` must be taken and owned by the caller, which hands them to the guard. Since `mask` is all
+    /// the bits, no other guard can reach the value while the returned one lives.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn guard(&self, mask: L) -> LockResult<Guard<'_, &mut T, L>> {
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&self.shared.state, &mask);
+        let poisoned = mask.is_locked(&self.shared.poison, true);
+        let value = Value::new(self.value.get());
+        let source = Source::Whole(value, whole::<T>);
+        let inner = Inner(&self.shared, Borrow::Own(mask), thread::panicking());
+        poison(
+            Guard(
+                Item::new(unsafe { &mut *value.get::<T>() }),
+                inner,
+                Some(source),
+            ),
+            poisoned,
+        )
+    }
+}
+
+impl<T: ?Sized, L: Lock> Multex<T, L> {
+    #[inline]
+    pub const fn as_ptr(&self) -> *const T {
+        self.value.get()
+    }
+
+    #[inline]
+    pub const fn as_mut_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    #[inline]
+    pub fn lock_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+    ) -> LockResult<Guard<'a, G::Item, L>> {
+        forever(self.acquire_with(key, partial, Wait::Forever))
+    }
+
+    /// Same as [`Multex::lock_with`], but the thread waits in the queue of the multex, where it is served before
+    /// the waiters of a lower `priority` with overlapping keys. Threads that do not lock with a priority may still
+    /// barge in unless the multex is [`Fairness::Fifo`], in which case they wait with a priority of 0.
+    #[inline]
+    pub fn lock_with_priority<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        priority: u8,
+    ) -> LockResult<Guard<'a, G::Item, L>> {
+        forever(self.acquire_with_priority(key, priority, Wait::Forever))
+    }
+
+    /// Same as [`Multex::lock_with_priority`], but waits as long as `wait` allows and reports why the indices could
+    /// not be taken. A poisoned guard is handed back through [`LockError::Poisoned`].
+    #[inline]
+    pub fn acquire_with_priority<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        priority: u8,
+        wait: Wait,
+    ) -> Acquired<'a, G::Item, L> {
+        let (mask, taken) = (&key.mask, &mut key.taken);
+        lock_at(&self.shared, mask, taken, false, Some(priority), wait).map_err(failed)?;
+        checked(unsafe { self.guard_with(key) })
+    }
+
+    #[inline]
+    pub fn try_lock_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+    ) -> Option<LockResult<Guard<'a, G::Item, L>>> {
+        self.lock_with_wait(key, partial, Wait::Never)
+    }
+
+    /// Takes a single free index of `key` and returns it along with its item. Blocks only while all the indices of
+    /// `key` are locked, which makes it suitable to claim one of many interchangeable resources.
+    ///
+    /// # Panics
+    /// Panics if `key` has no index.
+    #[inline]
+    pub fn lock_any_with<'a, G>(&'a self, key: &Key<L, G>) -> Claimed<'a, T, L>
+    where
+        usize: Get<'a, T>,
+    {
+        match self.acquire_any_with(key, Wait::Forever) {
+            Ok(claim) => Ok(claim),
+            Err(LockError::Poisoned(claim)) => Err(PoisonError::new(claim)),
+            Err(LockError::EmptyKey) => panic!("the key must have at least one index"),
+            Err(LockError::Closed | LockError::Cancelled) => panic!("the multex is closed"),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Takes at most `max` of the free indices of `key`, waiting until at least `min` of them can be taken at once.
+    /// [`Guard::mask`] reports the indices that were taken and the items of the other indices are missing.
+    ///
+    /// # Panics
+    /// Panics if `min` is greater than `max` or than the number of indices of `key`.
+    #[inline]
+    pub fn lock_some_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        min: usize,
+        max: usize,
+    ) -> LockResult<Guard<'a, G::Item, L>> {
+        forever(self.acquire_some_with(key, min, max, Wait::Forever))
+    }
+
+    /// Same as [`Multex::try_lock_with`], but reports the bits of `key` that were found locked by other guards on
+    /// failure. The bits are observed after the attempt failed such that they may already have been released.
+    #[inline]
+    pub fn try_lock_with_report<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> Acquired<'a, G::Item, L> {
+        self.acquire_with(key, false, Wait::Never)
+    }
+
+    /// Same as [`Multex::lock_with`], but waits as long as `wait` allows and reports why the indices could not be
+    /// taken. A poisoned guard is handed back through [`LockError::Poisoned`].
+    #[inline]
+    pub fn acquire_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+        wait: Wait,
+    ) -> Acquired<'a, G::Item, L> {
+        lock(&self.shared, &key.mask, &mut key.taken, partial, wait).map_err(failed)?;
+        checked(unsafe { self.guard_with(key) })
+    }
+
+    /// Same as [`Multex::lock_any_with`], but waits as long as `wait` allows. An empty `key` fails with
+    /// [`LockError::EmptyKey`].
+    #[inline]
+    pub fn acquire_any_with<'a, G>(
+        &'a self,
+        key: &Key<L, G>,
+        wait: Wait,
+    ) -> Result<Claim<'a, T, L>, LockError<Claim<'a, T, L>, L>>
+    where
+        usize: Get<'a, T>,
+    {
+        let mut mask = L::new();
+        let state = &self.shared.state;
+        lock_by(
+            &self.shared,
+            &key.mask,
+            &mut mask,
+            1,
+            wait,
+            |taken, wait| key.mask.lock_any(state, taken, wait),
+        )
+        .map_err(failed)?;
+        let Some(index) = mask.indices().next() else {
+            unreachable!()
+        };
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&self.shared.state, &mask);
+        let poisoned = mask.is_locked(&self.shared.poison, true);
+        let value = Value::new(self.value.get());
+        let item = unsafe { index.get(value.get(), |_| true) };
+        let source = Source::Key(value, index as *const (), claimed::<T>, PhantomData);
+        let inner = Inner(&self.shared, Borrow::Own(mask), thread::panicking());
+        let guard = Guard(Item::new(item), inner, Some(source));
+        checked(poison((index, guard), poisoned))
+    }
+
+    /// Same as [`Multex::lock_some_with`], but waits as long as `wait` allows.
+    ///
+    /// # Panics
+    /// Panics if `min` is greater than `max` or than the number of indices of `key`.
+    #[inline]
+    pub fn acquire_some_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        min: usize,
+        max: usize,
+        wait: Wait,
+    ) -> Acquired<'a, G::Item, L> {
+        assert!(
+            min <= max && min <= key.mask.indices().count(),
+            "the minimum must be reachable"
+        );
+        let state = &self.shared.state;
+        let (mask, taken) = (&key.mask, &mut key.taken);
+        lock_by(&self.shared, mask, taken, min, wait, |taken, wait| {
+            mask.lock_some(state, taken, min, max, wait)
+        })
+        .map_err(failed)?;
+        checked(unsafe { self.guard_with(key) })
+    }
+
+    #[inline]
+    pub fn lock_with_for<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+        timeout: Duration,
+    ) -> Option<LockResult<Guard<'a, G::Item, L>>> {
+        self.lock_with_wait(key, partial, Wait::after(timeout))
+    }
+
+    #[inline]
+    pub fn lock_with_until<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+        until: Instant,
+    ) -> Option<LockResult<Guard<'a, G::Item, L>>> {
+        self.lock_with_wait(key, partial, Wait::Until(until))
+    }
+
+    /// Same as [`Multex::lock_with`], but the returned guard owns a clone of the [`Arc`] and the `key`, which can be
+    /// taken back with [`OwnedGuard::into_key`].
+    #[inline]
+    pub fn lock_with_arc<G>(
+        self: &Arc<Self>,
+        key: Key<L, G>,
+        partial: bool,
+    ) -> LockResult<OwnedGuard<T, L, G>> {
+        forever(self.acquire_with_arc(key, partial, Wait::Forever))
+    }
+
+    /// Same as [`Multex::lock_with_arc`], but waits as long as `wait` allows and reports why the indices could not be
+    /// taken. The `key` is dropped along with the error unless it is handed back through [`LockError::Poisoned`].
+    #[inline]
+    pub fn acquire_with_arc<G>(
+        self: &Arc<Self>,
+        mut key: Key<L, G>,
+        partial: bool,
+        wait: Wait,
+    ) -> OwnedAcquired<T, L, G> {
+        lock(&self.shared, &key.mask, &mut key.taken, partial, wait).map_err(failed)?;
+        checked(unsafe { self.owned_guard(key) })
+    }
+
+    /// Same as [`Multex::lock_with`], but the returned future is woken when overlapping bits are released instead of
+    /// blocking the thread.
+    #[inline]
+    pub fn lock_with_async<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+    ) -> LockWithFuture<'a, T, G, L> {
+        LockWithFuture(self, Some(key), partial, None)
+    }
+
+    /// Same as [`Multex::lock_with_async`], but the returned future reports why the bits could not be taken like
+    /// [`Multex::acquire_with`] does.
+    #[inline]
+    pub fn acquire_with_async<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+    ) -> AcquireWithFuture<'a, T, G, L> {
+        AcquireWithFuture(self.lock_with_async(key, partial))
+    }
+
+    /// Forcefully unlocks the bits contained in the provided `mask`. A normal usage of a [`Multex`] normally doesn't require to unlock
+    /// manually since the [`Guard`] already does it automatically. This method is mainly meant to be used when
+    /// [`std::mem::forget(guard)`] is used.
+    ///
+    /// # Safety
+    /// This method is marked as `unsafe` because it may unlock bits that are still locked by a [`Guard`]. A wrong usage of unlock will
+    /// allow multiple concurrent mutable references to exist, thus causing undefined behavior.
+    #[inline]
+    pub unsafe fn unlock_with(&self, mask: &L) {
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::release(&self.shared.state, mask);
+        unlock(&self.shared, mask);
+    }
+
+    #[inline]
+    pub fn is_locked_with<'a, G: Get<'a, T>>(&self, key: &Key<L, G>, partial: bool) -> bool {
+        key.mask.is_locked(&self.shared.state, partial)
+    }
+
+    /// Returns the number of threads that wait in the queue of the multex, which holds the ones that block for all the
+    /// bits of their key unless the multex reserves them or takes them in order, and the ones that lock with a
+    /// priority.
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.shared.queued.count()
+    }
+
+    /// Closes the multex such that it hands out no more indices. New locks fail with [`LockError::Closed`] and the
+    /// pending ones, including the futures that are woken right away, fail with [`LockError::Cancelled`] once they
+    /// get to take their bits, which they release. The guards that are already held are not affected. The locks that
+    /// can not report a failure, such as [`Multex::lock`], panic instead.
+    #[inline]
+    pub fn close(&self) {
+        self.shared.closed.store(true, SeqCst);
+        self.shared.wakers.wake_all();
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(SeqCst)
+    }
+
+    /// Returns whether the indices of `key` are reserved by the blocked threads of a [`Fairness::Reserve`] multex.
+    #[inline]
+    pub fn is_reserved_with<'a, G: Get<'a, T>>(&self, key: &Key<L, G>, partial: bool) -> bool {
+        key.mask.is_locked(&self.shared.reserved, partial)
+    }
+
+    /// Returns whether the indices of `key` were held by a [`Guard`] that was dropped during a panic.
+    #[inline]
+    pub fn is_poisoned_with<'a, G: Get<'a, T>>(&self, key: &Key<L, G>, partial: bool) -> bool {
+        key.mask.is_locked(&self.shared.poison, partial)
+    }
+
+    /// Clears the poison of the indices of `key` such that locking them succeeds again.
+    #[inline]
+    pub fn clear_poison_with<'a, G: Get<'a, T>>(&self, key: &Key<L, G>) {
+        key.mask.unlock(&self.shared.poison, false);
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    #[inline]
+    pub fn get_mut_with<'a, G: Get<'a, T>>(&mut self, key: &mut Key<L, G>) -> G::Item {
+        key.taken.clear();
+        let value = self.value.get_mut();
+        unsafe { key.indices.get(value, |index| key.taken.add(index)) }
+    }
+
+    #[inline]
+    unsafe fn owned_guard<G>(self: &Arc<Self>, key: Key<L, G>) -> LockResult<OwnedGuard<T, L, G>> {
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&self.shared.state, &key.taken);
+        let poisoned = key.taken.is_locked(&self.shared.poison, true);
+        poison(OwnedGuard(self.clone(), key, thread::panicking()), poisoned)
+    }
+
+    #[inline]
+    fn lock_with_wait<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        partial: bool,
+        wait: Wait,
+    ) -> Option<LockResult<Guard<'a, G::Item, L>>> {
+        if lock(&self.shared, &key.mask, &mut key.taken, partial, wait).is_ok() {
+            Some(unsafe { self.guard_with(key) })
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    unsafe fn guard_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> LockResult<Guard<'a, G::Item, L>> {
+        #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+        deadlock::hold(&self.shared.state, &key.taken);
+        let poisoned = key.taken.is_locked(&self.shared.poison, true);
+        let Key { taken, indices, .. } = key;
+        let value = Value::new(self.value.get());
+        let item = indices.get(value.get(), |index| taken.has(index));
+        let indices = &*indices as *const G as *const ();
+        let source = Source::Key(value, indices, keyed::<T, G>, PhantomData);
+        let inner = Inner(&self.shared, Borrow::Mut(taken), thread::panicking());
+        poison(Guard(Item::new(item), inner, Some(source)), poisoned)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAll> LockFuture<'a, T, L> {
+    /// Same as [`Future::poll`], but reports why the bits could not be taken.
+    fn acquire(&mut self, context: &mut Context<'_>) -> Poll<Acquired<'a, &'a mut T, L>> {
+        let LockFuture(slot, id) = self;
+        let Some(multex) = slot.take() else {
+            panic!("the future was polled after completion")
+        };
+        let (shared, mut mask) = (&multex.shared, L::ALL);
+        let locked = lock(shared, &L::ALL, &mut mask, false, Wait::Never);
+        match pending(shared, &L::ALL, &mut mask, false, id, context, locked) {
+            Some(locked) => Poll::Ready(
+                locked
+                    .map_err(failed)
+                    .and_then(|()| checked(unsafe { multex.guard(mask) })),
+            ),
+            None => {
+                *slot = Some(multex);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAll> Future for LockFuture<'a, T, L> {
+    type Output = LockResult<Guard<'a, &'a mut T, L>>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().acquire(context).map(forever)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAll> Future for AcquireFuture<'a, T, L> {
+    type Output = Acquired<'a, &'a mut T, L>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().0.acquire(context)
+    }
+}
+
+/// Settles the attempt of a future to take the bits of `mask`, which ended with `locked`. A conflict registers the
+/// waker of the future and tries again, and returns `None` if the future is still pending. Otherwise, the waker is
+/// unregistered and a future that was already pending when its multex was closed is cancelled.
+#[inline]
+fn pending<L: Lock>(
+    shared: &Shared<L>,
+    mask: &L,
+    taken: &mut L,
+    partial: bool,
+    id: &mut Option<usize>,
+    context: &Context,
+    locked: Locked<L>,
+) -> Option<Locked<L>> {
+    let settled = |locked: &Locked<L>| {
+        matches!(
+            locked,
+            Ok(()) | Err(LockError::Closed | LockError::Cancelled)
+        )
+    };
+    let locked = if settled(&locked) {
+        locked
+    } else {
+        let identifier = *id.get_or_insert_with(system::identifier);
+        shared.wakers.register(identifier, mask, context.waker());
+        retry(shared, mask, taken, partial)
+    };
+    if !settled(&locked) {
+        return None;
+    }
+
+    let Some(id) = id.take() else {
+        return Some(locked);
+    };
+    shared.wakers.unregister(id);
+    match locked {
+        Err(LockError::Closed) => Some(Err(LockError::Cancelled)),
+        locked => Some(locked),
+    }
+}
+
+impl<T: ?Sized, L: Lock> Drop for LockFuture<'_, T, L> {
+    #[inline]
+    fn drop(&mut self) {
+        if let (Some(multex), Some(id)) = (self.0, self.1.take()) {
+            multex.shared.wakers.unregister(id);
+        }
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: Lock> LockWithFuture<'a, T, G, L> {
+    /// Same as [`Future::poll`], but reports why the bits could not be taken.
+    fn acquire(&mut self, context: &mut Context<'_>) -> Poll<Acquired<'a, G::Item, L>> {
+        let LockWithFuture(multex, slot, partial, id) = self;
+        let Some(key) = slot.take() else {
+            panic!("the future was polled after completion")
+        };
+        let shared = &multex.shared;
+        let (mask, taken) = (&key.mask, &mut key.taken);
+        let locked = lock(shared, mask, taken, *partial, Wait::Never);
+        match pending(shared, mask, taken, *partial, id, context, locked) {
+            Some(locked) => Poll::Ready(
+                locked
+                    .map_err(failed)
+                    .and_then(|()| checked(unsafe { multex.guard_with(key) })),
+            ),
+            None => {
+                *slot = Some(key);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: Lock> Future for LockWithFuture<'a, T, G, L> {
+    type Output = LockResult<Guard<'a, G::Item, L>>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().acquire(context).map(forever)
+    }
+}
+
+impl<'a, T: ?Sized, G: Get<'a, T>, L: Lock> Future for AcquireWithFuture<'a, T, G, L> {
+    type Output = Acquired<'a, G::Item, L>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().0.acquire(context)
+    }
+}
+
+impl<T: ?Sized, G, L: Lock> Drop for LockWithFuture<'_, T, G, L> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(id) = self.3.take() {
+            self.0.shared.wakers.unregister(id);
+        }
+    }
+}
+
+impl MultexCondvar {
+    #[inline]
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    /// Releases exactly the bits of `guard` until this condition variable is notified and takes them back before
+    /// returning, waiting for them if necessary. Like other condition variables, it may wake up spuriously.
+    ///
+    /// # Panics
+    /// Panics if the guard was mapped, merged or split, since its items cannot be produced again.
+    pub fn wait<'a, T, L: Lock>(&self, guard: Guard<'a, T, L>) -> LockResult<Guard<'a, T, L>> {
+        let value = self.0.load(Acquire);
+        let mut guard = guard;
+        Guard::unlocked(&mut guard, || system::wait(&self.0, value, u64::MAX, None));
+        let poisoned = guard.1 .1.is_locked(&guard.1 .0.poison, true);
+        poison(guard, poisoned)
+    }
+
+    /// Waits on `guard` for as long as `condition` holds for its items.
+    pub fn wait_while<'a, T, L: Lock, F: FnMut(&mut T) -> bool>(
+        &self,
+        mut guard: Guard<'a, T, L>,
+        mut condition: F,
+    ) -> LockResult<Guard<'a, T, L>> {
+        while condition(&mut guard.0) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    #[inline]
+    pub fn notify_one(&self) {
+        self.0.fetch_add(1, Release);
+        system::wake_one(&self.0);
+    }
+
+    #[inline]
+    pub fn notify_all(&self) {
+        self.0.fetch_add(1, Release);
+        system::wake(&self.0, u64::MAX);
+    }
+}
+
+impl Default for MultexCondvar {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized, L: Lock, G> Part for (&Multex<T, L>, &mut Key<L, G>) {
+    #[inline]
+    fn take(&mut self, wait: Wait) -> Result<(), LockError> {
+        let (multex, key) = self;
+        match lock(&multex.shared, &key.mask, &mut key.taken, false, wait) {
+            Ok(()) => Ok(()),
+            Err(LockError::Closed) => Err(LockError::Closed),
+            Err(LockError::Cancelled) => Err(LockError::Cancelled),
+            Err(_) => Err(LockError::WouldBlock { conflicting: () }),
+        }
+    }
+
+    #[inline]
+    fn untake(&mut self) {
+        let (multex, key) = self;
+        unlock(&multex.shared, &key.taken);
+        key.taken.clear();
+    }
+
+    #[inline]
+    fn key(&self) -> (*const (), Vec<usize>) {
+        let (multex, key) = self;
+        (
+            ptr::addr_of!(multex.shared).cast(),
+            key.mask.indices().collect(),
+        )
+    }
+}
+
+macro_rules! many {
+    ($($p:ident, $t:ident, $l:ident, $g:ident),*) => {
+        impl<'a, $($t: ?Sized, $l: Lock, $g: Get<'a, $t>,)*> Many<'a>
+            for ($((&'a Multex<$t, $l>, &'a mut Key<$l, $g>),)*)
+        {
+            type Guards = ($(Guard<'a, $g::Item, $l>,)*);
+
+            #[inline]
+            fn acquire(self) -> Result<Self::Guards, LockError<Self::Guards>> {
+                let ($(mut $p,)*) = self;
+                take_all(&mut [$(&mut $p as &mut dyn Part),*]).map_err(failed)?;
+                let mut poisoned = false;
+                let guards = ($(
+                    unsafe { $p.0.guard_with($p.1) }.unwrap_or_else(|error| {
+                        poisoned = true;
+                        error.into_inner()
+                    }),
+                )*);
+                checked(poison(guards, poisoned))
+            }
+        }
+    };
+}
+
+many!(p0, T0, L0, G0, p1, T1, L1, G1);
+many!(p0, T0, L0, G0, p1, T1, L1, G1, p2, T2, L2, G2);
+many!(p0, T0, L0, G0, p1, T1, L1, G1, p2, T2, L2, G2, p3, T3, L3, G3);
+many!(p0, T0, L0, G0, p1, T1, L1, G1, p2, T2, L2, G2, p3, T3, L3, G3, p4, T4, L4, G4);
+many!(
+    p0, T0, L0, G0, p1, T1, L1, G1, p2, T2, L2, G2, p3, T3, L3, G3, p4, T4, L4, G4, p5, T5, L5, G5
+);
+
+/// Takes the keys of several multexes at once, such as `lock_many(((&entities, &mut key1), (&components, &mut key2)))`,
+/// and returns one guard per key. No key is held while waiting for another, so the keys can't deadlock with each other
+/// regardless of the order in which other threads take them.
+///
+/// # Panics
+/// Panics if two keys on the same multex overlap, since they could never be held at once.
+#[inline]
+pub fn lock_many<'a, M: Many<'a>>(many: M) -> LockResult<M::Guards> {
+    forever(many.acquire())
+}
+
+/// Same as [`lock_many`], but reports why the keys could not be taken. No key is held when it fails.
+///
+/// # Panics
+/// Panics if two keys on the same multex overlap.
+#[inline]
+pub fn acquire_many<'a, M: Many<'a>>(many: M) -> Result<M::Guards, LockError<M::Guards>> {
+    many.acquire()
+}
+
+/// Takes the keys of all the `parts` without holding any of them while blocking. A single part is waited for at a
+/// time and the others are tried without waiting; when one of them is busy, everything is released and the busy
+/// part becomes the one to wait for. A part whose multex is closed fails them all.
+fn take_all(parts: &mut [&mut dyn Part]) -> Result<(), LockError> {
+    let keys = parts.iter().map(|part| part.key()).collect::<Vec<_>>();
+    for (index, (address, indices)) in keys.iter().enumerate() {
+        for (other, others) in &keys[index + 1..] {
+            assert!(
+                address != other || !indices.iter().any(|index| others.contains(index)),
+                "the keys on the same multex must not overlap"
+            );
+        }
+    }
+
+    let mut first = 0;
+    'outer: loop {
+        parts[first].take(Wait::Forever)?;
+        for index in 0..parts.len() {
+            let locked = if index == first {
+                Ok(())
+            } else {
+                parts[index].take(Wait::Never)
+            };
+            let Err(error) = locked else {
+                continue;
+            };
+
+            for taken in (0..index).filter(|&taken| taken != first) {
+                parts[taken].untake();
+            }
+            parts[first].untake();
+            if let LockError::Closed | LockError::Cancelled = error {
+                return Err(error);
+            }
+            first = index;
+            continue 'outer;
+        }
+        break Ok(());
+    }
+}
+
+impl<T, L: RwLock> RwMultex<T, L> {
+    #[inline]
+    pub const fn new(values: T) -> Self {
+        Self {
+            counts: L::COUNTS,
+            value: UnsafeCell::new(values),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: RwLock + LockAll> RwMultex<T, L> {
+    #[inline]
+    pub fn read(&self) -> ReadGuard<'_, &T, L> {
+        match self.acquire_read(Wait::Forever) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn try_read(&self) -> Option<ReadGuard<'_, &T, L>> {
+        self.acquire_read(Wait::Never).ok()
+    }
+
+    #[inline]
+    pub fn read_for(&self, timeout: Duration) -> Option<ReadGuard<'_, &T, L>> {
+        self.acquire_read(Wait::after(timeout)).ok()
+    }
+
+    #[inline]
+    pub fn read_until(&self, until: Instant) -> Option<ReadGuard<'_, &T, L>> {
+        self.acquire_read(Wait::Until(until)).ok()
+    }
+
+    #[inline]
+    pub fn write(&self) -> WriteGuard<'_, &mut T, L> {
+        match self.acquire_write(Wait::Forever) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn try_write(&self) -> Option<WriteGuard<'_, &mut T, L>> {
+        self.acquire_write(Wait::Never).ok()
+    }
+
+    #[inline]
+    pub fn write_for(&self, timeout: Duration) -> Option<WriteGuard<'_, &mut T, L>> {
+        self.acquire_write(Wait::after(timeout)).ok()
+    }
+
+    #[inline]
+    pub fn write_until(&self, until: Instant) -> Option<WriteGuard<'_, &mut T, L>> {
+        self.acquire_write(Wait::Until(until)).ok()
+    }
+
+    /// Same as [`RwMultex::read`], but waits as long as `wait` allows and reports why the indices could not be taken.
+    #[inline]
+    pub fn acquire_read(&self, wait: Wait) -> Result<ReadGuard<'_, &T, L>, LockError<(), L>> {
+        let mut mask = L::ALL;
+        L::ALL.read(&self.counts, &mut mask, wait)?;
+        Ok(unsafe { self.read_guard(mask) })
+    }
+
+    /// Same as [`RwMultex::write`], but waits as long as `wait` allows and reports why the indices could not be taken.
+    #[inline]
+    #[allow(clippy::mut_from_ref)] // The reference is only reachable through the guard that holds all the bits.
+    pub fn acquire_write(&self, wait: Wait) -> Result<WriteGuard<'_, &mut T, L>, LockError<(), L>> {
+        let mut mask = L::ALL;
+        L::ALL.write(&self.counts, &mut mask, wait)?;
+        Ok(unsafe { self.write_guard(mask) })
+    }
+
+    #[inline]
+    unsafe fn read_guard(&self, mask: L) -> ReadGuard<'_, &T, L> {
+        let inner = RwInner(&self.counts, Borrow::Own(mask), Mode::Read);
+        ReadGuard(unsafe { &*self.value.get() }, inner)
+    }
+
+    /// # Safety
+    /// The write bits of `mask` must be taken and owned by the caller, which hands them to the guard. Since `mask` is
+    /// all the bits, no other guard can reach the value while the returned one lives.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn write_guard(&self, mask: L) -> WriteGuard<'_, &mut T, L> {
+        let inner = RwInner(&self.counts, Borrow::Own(mask), Mode::Write);
+        WriteGuard(unsafe { &mut *self.value.get() }, inner)
+    }
+}
+
+impl<T: ?Sized, L: RwLock> RwMultex<T, L> {
+    #[inline]
+    pub const fn as_ptr(&self) -> *const T {
+        self.value.get()
+    }
+
+    #[inline]
+    pub const fn as_mut_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    #[inline]
+    pub fn read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> ReadGuard<'a, G::Read, L> {
+        match self.acquire_read_with(key, Wait::Forever) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn try_read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> Option<ReadGuard<'a, G::Read, L>> {
+        self.acquire_read_with(key, Wait::Never).ok()
+    }
+
+    #[inline]
+    pub fn read_with_for<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        timeout: Duration,
+    ) -> Option<ReadGuard<'a, G::Read, L>> {
+        self.acquire_read_with(key, Wait::after(timeout)).ok()
+    }
+
+    #[inline]
+    pub fn read_with_until<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        until: Instant,
+    ) -> Option<ReadGuard<'a, G::Read, L>> {
+        self.acquire_read_with(key, Wait::Until(until)).ok()
+    }
+
+    #[inline]
+    pub fn write_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> WriteGuard<'a, G::Item, L> {
+        match self.acquire_write_with(key, Wait::Forever) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn try_write_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> Option<WriteGuard<'a, G::Item, L>> {
+        self.acquire_write_with(key, Wait::Never).ok()
+    }
+
+    #[inline]
+    pub fn write_with_for<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        timeout: Duration,
+    ) -> Option<WriteGuard<'a, G::Item, L>> {
+        self.acquire_write_with(key, Wait::after(timeout)).ok()
+    }
+
+    #[inline]
+    pub fn write_with_until<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        until: Instant,
+    ) -> Option<WriteGuard<'a, G::Item, L>> {
+        self.acquire_write_with(key, Wait::Until(until)).ok()
+    }
+
+    #[inline]
+    pub fn upgradable_read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> UpgradableGuard<'a, T, G, L> {
+        match self.acquire_upgradable_read_with(key, Wait::Forever) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn try_upgradable_read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> Option<UpgradableGuard<'a, T, G, L>> {
+        self.acquire_upgradable_read_with(key, Wait::Never).ok()
+    }
+
+    #[inline]
+    pub fn upgradable_read_with_for<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        timeout: Duration,
+    ) -> Option<UpgradableGuard<'a, T, G, L>> {
+        self.acquire_upgradable_read_with(key, Wait::after(timeout))
+            .ok()
+    }
+
+    #[inline]
+    pub fn upgradable_read_with_until<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        until: Instant,
+    ) -> Option<UpgradableGuard<'a, T, G, L>> {
+        self.acquire_upgradable_read_with(key, Wait::Until(until))
+            .ok()
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Same as [`RwMultex::read_with`], but waits as long as `wait` allows and reports why the indices could not
+    /// be taken.
+    #[inline]
+    pub fn acquire_read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        wait: Wait,
+    ) -> Result<ReadGuard<'a, G::Read, L>, LockError<(), L>> {
+        key.mask.read(&self.counts, &mut key.taken, wait)?;
+        Ok(unsafe { self.read_guard_with(key) })
+    }
+
+    /// Same as [`RwMultex::write_with`], but waits as long as `wait` allows and reports why the indices could not
+    /// be taken.
+    #[inline]
+    pub fn acquire_write_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        wait: Wait,
+    ) -> Result<WriteGuard<'a, G::Item, L>, LockError<(), L>> {
+        key.mask.write(&self.counts, &mut key.taken, wait)?;
+        Ok(unsafe { self.write_guard_with(key) })
+    }
+
+    /// Same as [`RwMultex::upgradable_read_with`], but waits as long as `wait` allows and reports why the indices could not
+    /// be taken.
+    #[inline]
+    pub fn acquire_upgradable_read_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+        wait: Wait,
+    ) -> Result<UpgradableGuard<'a, T, G, L>, LockError<(), L>> {
+        key.mask.upgradable(&self.counts, &mut key.taken, wait)?;
+        Ok(unsafe { self.upgradable_guard_with(key) })
+    }
+
+    #[inline]
+    unsafe fn upgradable_guard_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> UpgradableGuard<'a, T, G, L> {
+        let inner = RwInner(&self.counts, Borrow::Mut(&mut key.taken), Mode::Upgrade);
+        Upgrade(&self.value, &key.indices, inner).read()
+    }
+
+    #[inline]
+    unsafe fn read_guard_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> ReadGuard<'a, G::Read, L> {
+        let item = key
+            .indices
+            .read(self.value.get(), |index| key.taken.has(index));
+        let inner = RwInner(&self.counts, Borrow::Mut(&mut key.taken), Mode::Read);
+        ReadGuard(item, inner)
+    }
+
+    #[inline]
+    unsafe fn write_guard_with<'a, G: Get<'a, T>>(
+        &'a self,
+        key: &'a mut Key<L, G>,
+    ) -> WriteGuard<'a, G::Item, L> {
+        let item = key
+            .indices
+            .get(self.value.get(), |index| key.taken.has(index));
+        let inner = RwInner(&self.counts, Borrow::Mut(&mut key.taken), Mode::Write);
+        WriteGuard(item, inner)
+    }
+}
+
+/// Releases the bits of `mask` with `unlock` and marks them as poisoned if the thread started panicking since they were
+/// taken, as told by `panicking`.
+#[inline]
+fn release<L: Lock>(shared: &Shared<L>, mask: &mut L, panicking: bool, unlock: fn(&Shared<L>, &L)) {
+    #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+    deadlock::release(&shared.state, mask);
+    if !panicking && thread::panicking() {
+        let _ = mask.lock(&shared.poison, &mut L::new(), true, Wait::Never);
+    }
+    unlock(shared, mask);
+    mask.clear();
+}
+
+/// Releases the bits of `mask`, grants them to the queued threads of the multex and wakes the futures that wait for
+/// them.
+#[inline]
+fn unlock<L: Lock>(shared: &Shared<L>, mask: &L) {
+    mask.unlock(&shared.state, true);
+    queue::grant::<L>(&shared.state, &shared.queued);
+    shared.wakers.wake(mask);
+}
+
+/// Same as [`unlock`], but hands the bits of `mask` over to the queued threads that wait for them.
+#[inline]
+fn hand_off<L: Lock>(shared: &Shared<L>, mask: &L) {
+    let released = queue::hand_off(&shared.state, &shared.queued, mask);
+    shared.wakers.wake(&released);
+}
+
+/// Turns a failure of [`Lock`], which never hands out a guard, into the error of a lock that would have returned `G`.
+#[inline]
+fn failed<G, L>(error: LockError<(), L>) -> LockError<G, L> {
+    error.map(|()| unreachable!())
+}
+
+/// Reports the result of a lock that waits forever through a [`LockResult`], since it can only fail by poison unless
+/// its multex was closed.
+///
+/// # Panics
+/// Panics if the multex was closed.
+#[inline]
+fn forever<G, L>(result: Result<G, LockError<G, L>>) -> LockResult<G> {
+    match result {
+        Ok(guard) => Ok(guard),
+        Err(LockError::Poisoned(guard)) => Err(PoisonError::new(guard)),
+        Err(LockError::Closed | LockError::Cancelled) => panic!("the multex is closed"),
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Reports the poison of a lock through [`LockError::Poisoned`].
+#[inline]
+fn checked<G, L>(result: LockResult<G>) -> Result<G, LockError<G, L>> {
+    result.map_err(|error| LockError::Poisoned(error.into_inner()))
+}
+
+#[inline]
+fn poison<G>(guard: G, poisoned: bool) -> LockResult<G> {
+    if poisoned {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
+
+/// Takes the bits of `mask` and reports the wait to the deadlock detector when it is enabled. Only the threads that
+/// would otherwise wait forever are reported.
+#[inline]
+fn lock<L: Lock>(
+    shared: &Shared<L>,
+    mask: &L,
+    taken: &mut L,
+    partial: bool,
+    wait: Wait,
+) -> Locked<L> {
+    lock_at(shared, mask, taken, partial, None, wait)
+}
+
+/// Same as [`lock`], but a thread with a `priority` waits in the queue of the multex whatever its [`Fairness`].
+fn lock_at<L: Lock>(
+    shared: &Shared<L>,
+    mask: &L,
+    taken: &mut L,
+    partial: bool,
+    priority: Option<u8>,
+    wait: Wait,
+) -> Locked<L> {
+    if shared.closed.load(SeqCst) {
+        return Err(LockError::Closed);
+    }
+
+    let mut entry = Entry::new(&shared.state, &shared.queued, mask);
+    let mut take = |wait| take(shared, mask, taken, partial, priority, wait, &mut entry);
+    let locked = if matches!(wait, Wait::Never) {
+        barged(shared, mask, take(wait))
+    } else if take(Wait::Never).is_ok() {
+        Ok(())
+    } else {
+        let need = mask.indices().count();
+        let locked = block(&shared.state, mask, need, true, wait, take);
+        // The thread keeps its place in the queue while the deadlock detector confirms its wait, and only leaves it
+        // here.
+        let locked = locked.or_else(|error| entry.leave(taken).then_some(()).ok_or(error));
+        barged(shared, mask, locked)
+    };
+    drop(entry);
+    opened(shared, taken, locked)
+}
+
+/// Takes the bits of `mask` the way [`lock_at`] does, without granting the queue again nor waking the futures when it
+/// fails. A thread that waits in the queue keeps its `entry` there when the wait expires.
+#[inline]
+fn take<L: Lock>(
+    shared: &Shared<L>,
+    mask: &L,
+    taken: &mut L,
+    partial: bool,
+    priority: Option<u8>,
+    wait: Wait,
+    entry: &mut Entry<L>,
+) -> Locked<L> {
+    let state = &shared.state;
+    let fair = !partial;
+    match (shared.fairness, priority) {
+        (_, Some(priority)) if fair => queue::lock(entry, taken, Some(priority), wait),
+        (Fairness::Fifo, None) if fair => queue::lock(entry, taken, Some(0), wait),
+        (Fairness::Reserve, None) if fair => reserve(shared, mask, taken, wait),
+        _ if !partial && shared.strategy == Strategy::Ordered => {
+            mask.lock_ordered(state, taken, wait)
+        }
+        // The threads that block wait in the queue without a priority, such that their bits can be handed over.
+        _ if !partial && !matches!(wait, Wait::Never) => queue::lock(entry, taken, None, wait),
+        _ => mask.lock(state, taken, partial, wait),
+    }
+}
+
+/// Tries again to take the bits of `mask` for a future that just registered its waker. A failure grants the queue
+/// again but does not wake the futures, which would wake this one right away.
+#[inline]
+fn retry<L: Lock>(shared: &Shared<L>, mask: &L, taken: &mut L, partial: bool) -> Locked<L> {
+    let mut entry = Entry::new(&shared.state, &shared.queued, mask);
+    let locked = take(shared, mask, taken, partial, None, Wait::Never, &mut entry);
+    drop(entry);
+    if locked.is_err() {
+        queue::grant::<L>(&shared.state, &shared.queued);
+    }
+    opened(shared, taken, locked)
+}
+
+/// Cancels a lock that ends after its multex was closed, releasing the bits of `taken` if it succeeded.
+#[inline]
+fn opened<L: Lock>(shared: &Shared<L>, taken: &mut L, locked: Locked<L>) -> Locked<L> {
+    if !shared.closed.load(SeqCst) {
+        return locked;
+    }
+    if locked.is_ok() {
+        unlock(shared, taken);
+        taken.clear();
+    }
+    Err(LockError::Cancelled)
+}
+
+/// Waits as long as `wait` allows for `take`, which must take at least `need` of the indices of `mask`. A wait that can
+/// never end is reported when the deadlock detector is enabled, only once it makes no progress for
+/// [`deadlock::CONFIRM`] since the guards that block it may have been sent to other threads. Cycles are only searched
+/// if the wait is `reported`.
+fn block<L: Lock>(
+    state: &L::State,
+    mask: &L,
+    need: usize,
+    reported: bool,
+    wait: Wait,
+    mut take: impl FnMut(Wait) -> Locked<L>,
+) -> Locked<L> {
+    #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+    let mut suspected = suspect(state, mask, need, reported, wait, None);
+    #[cfg(not(any(feature = "deadlock", feature = "reentrant")))]
+    let _ = (state, mask, need, reported);
+    #[cfg(any(feature = "deadlock", feature = "reentrant"))]
+    let locked = loop {
+        let Some(guards) = &suspected else {
+            break take(wait);
+        };
+        let until = Instant::now() + deadlock::CONFIRM;
+        let until = match wait {
+            Wait::Until(deadline) => until.min(deadline),
+            _ => until,
+        };
+        let locked = take(Wait::Until(until));
+        if locked.is_ok() || wait.expired() {
+            break locked;
+        }
+        suspected = suspect(state, mask, need, reported, wait, Some(guards));
+    };
+    #[cfg(not(any(feature = "deadlock", feature = "reentrant")))]
+    let locked = take(wait);
+    #[cfg(feature = "deadlock")]
+    deadlock::cancel();
+    locked
+}
+
+/// Returns the guards that may prevent the wait of [`block`] from ever ending, and panics if they are the `suspected`
+/// ones.
+#[cfg(any(feature = "deadlock", feature = "reentrant"))]
+fn suspect<L: Lock>(
+    state: &L::State,
+    mask: &L,
+    need: usize,
+    reported: bool,
+    wait: Wait,
+    suspected: Option<&[u64]>,
+) -> Option<Vec<u64>> {
+    #[cfg(feature = "reentrant")]
+    if let Some(guards) = deadlock::reenter(state, mask, need, suspected) {
+        return Some(guards);
+    }
+    #[cfg(feature = "deadlock")]
+    if reported && matches!(wait, Wait::Forever) {
+        return deadlock::wait(state, mask, suspected);
+    }
+    let _ = (state, mask, need, reported, wait, suspected);
+    None
+}
+
+/// Takes the bits of `mask` unless another thread has reserved some of them. Otherwise, reserves all the bits of `mask`
+/// one at a time in ascending order before waiting for them, such that the free ones are not taken by later locks and
+/// two threads never wait for each other's reservations.
+fn reserve<L: Lock>(shared: &Shared<L>, mask: &L, taken: &mut L, wait: Wait) -> Locked<L> {
+    let state = &shared.state;
+    if !mask.is_locked(&shared.reserved, true)
+        && mask.lock(state, taken, false, Wait::Never).is_ok()
+    {
+        return Ok(());
+    }
+    if let Wait::Never = wait {
+        let mut conflicting = mask.conflicts(state);
+        conflicting.merge(&mask.conflicts(&shared.reserved));
+        return Err(LockError::WouldBlock { conflicting });
+    }
+
+    let mut reserved = L::new();
+    let mut locked = Ok(());
+    for index in mask.indices() {
+        let mut bit = L::new();
+        bit.add(index);
+        let mut taken = L::new();
+        locked = bit.lock(&shared.reserved, &mut taken, false, wait);
+        if locked.is_err() {
+            break;
+        }
+        reserved.merge(&taken);
+    }
+    if locked.is_ok() {
+        locked = mask.lock(state, taken, false, wait);
+    }
+    reserved.unlock(&shared.reserved, true);
+    locked
+}
+
+/// Same as [`lock`], but takes bits with `take`, which may be satisfied by any `need` of the indices of `mask`. Since the
+/// bits that will be taken are not known in advance, the wait is only checked for re-entrance.
+#[inline]
+fn lock_by<L: Lock>(
+    shared: &Shared<L>,
+    mask: &L,
+    taken: &mut L,
+    need: usize,
+    wait: Wait,
+    mut take: impl FnMut(&mut L, Wait) -> Locked<L>,
+) -> Locked<L> {
+    if shared.closed.load(SeqCst) {
+        return Err(LockError::Closed);
+    }
+
+    let locked = if matches!(wait, Wait::Never) {
+        barged(shared, mask, take(taken, wait))
+    } else if take(taken, Wait::Never).is_ok() {
+        Ok(())
+    } else {
+        let take = |wait| take(taken, wait);
+        let locked = block(&shared.state, mask, need, false, wait, take);
+        barged(shared, mask, locked)
+    };
+    opened(shared, taken, locked)
+}
+
+/// A lock that fails may have held or reserved some of the bits of `mask` for a moment while rolling back, such that
+/// the queue of the multex is granted again and the futures that wait for these bits are woken.
+#[inline]
+fn barged<L: Lock>(shared: &Shared<L>, mask: &L, locked: Locked<L>) -> Locked<L> {
+    if locked.is_err() {
+        queue::grant::<L>(&shared.state, &shared.queued);
+        shared.wakers.wake(mask);
     }
+    locked
 }
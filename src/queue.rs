@@ -0,0 +1,302 @@
+use crate::{
+    error::LockError,
+    lock::{Lock, Locked, Wait},
+};
+use std::{
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, AtomicU8, Ordering::*},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, Thread},
+    time::Instant,
+};
+
+/// The waiters of a multex in the queues.
+pub struct Queued {
+    count: AtomicU32,
+    /// Raised once a thread of the multex is queued, such that the releases skip [`grant`] and its fence until then.
+    raised: AtomicBool,
+}
+
+impl Queued {
+    #[inline]
+    pub const fn new(raised: bool) -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            raised: AtomicBool::new(raised),
+        }
+    }
+
+    /// The number of threads of the multex that wait in the queue.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count.load(Relaxed) as usize
+    }
+}
+
+/// A thread that waits in the queue of a multex for the `indices` of its key. A waiter with a `priority` reserves its
+/// bits, which are taken on its behalf by the thread that grants them. A waiter without one only waits for its bits to
+/// be free and takes them itself, unless they are handed over by [`hand_off`]. The thread is unparked once `signal`
+/// is set to [`WOKEN`] or [`GRANTED`].
+struct Waiter {
+    address: usize,
+    priority: Option<u8>,
+    indices: Vec<usize>,
+    thread: Thread,
+    signal: Arc<AtomicU8>,
+}
+
+/// The [`Waiter::signal`] of a waiter that was removed from the queue to try again.
+const WOKEN: u8 = 1;
+/// The [`Waiter::signal`] of a waiter whose bits were taken on its behalf.
+const GRANTED: u8 = 2;
+
+/// The number of queues that the multexes are spread over by the address of their state, such that unrelated multexes
+/// rarely contend on the same queue.
+const BUCKETS: usize = 64;
+#[allow(clippy::declare_interior_mutable_const)]
+const BUCKET: Mutex<Vec<Waiter>> = Mutex::new(Vec::new());
+/// The waiters of the multexes of each bucket by decreasing priority and then in arrival order, keyed by the address of
+/// their state.
+static QUEUES: [Mutex<Vec<Waiter>>; BUCKETS] = [BUCKET; BUCKETS];
+
+/// The place of a thread in the queue of a multex while it waits for the bits of `mask` of `state`, which it keeps
+/// across the timeouts of [`lock`] such that it does not rejoin the queue at the back. It must [`Entry::leave`] the
+/// queue once the thread stops waiting, which is otherwise done when it is dropped.
+pub struct Entry<'a, L: Lock> {
+    state: &'a L::State,
+    queued: &'a Queued,
+    mask: &'a L,
+    signal: Option<Arc<AtomicU8>>,
+}
+
+impl<'a, L: Lock> Entry<'a, L> {
+    #[inline]
+    pub const fn new(state: &'a L::State, queued: &'a Queued, mask: &'a L) -> Self {
+        Self {
+            state,
+            queued,
+            mask,
+            signal: None,
+        }
+    }
+
+    /// Leaves the queue after [`lock`] timed out. Returns `true` and stores the bits in `taken` if they were granted
+    /// in the meantime.
+    pub fn leave(&mut self, taken: &mut L) -> bool {
+        let Some(signal) = self.signal.take() else {
+            return false;
+        };
+        let mut queue = waiters(address(self.state));
+        match signal.load(Acquire) {
+            GRANTED => {
+                taken.clear();
+                taken.merge(self.mask);
+                return true;
+            }
+            WOKEN => {}
+            _ => {
+                queue.retain(|waiter| !Arc::ptr_eq(&waiter.signal, &signal));
+                self.queued.count.fetch_sub(1, Relaxed);
+            }
+        }
+        // The bits that were reserved by this waiter may now be granted to the ones behind it.
+        grant_locked(&mut queue, self.state, self.queued, &mut L::new());
+        false
+    }
+}
+
+impl<L: Lock> Drop for Entry<'_, L> {
+    fn drop(&mut self) {
+        let mut taken = L::new();
+        if self.leave(&mut taken) {
+            taken.unlock(self.state, true);
+            grant::<L>(self.state, self.queued);
+        }
+    }
+}
+
+/// Takes the bits of the mask of `entry` in order, such that they are not taken while a waiter with an overlapping key
+/// and the same or a higher `priority` waits ahead of it. Without a `priority`, the bits are taken as soon as they are
+/// free instead, and the thread only waits in the queue such that the bits can be handed over to it. The queue counts
+/// its waiters for the releases to know when to [`grant`]. The thread stays in the queue if `wait` expires.
+pub fn lock<L: Lock>(
+    entry: &mut Entry<L>,
+    taken: &mut L,
+    priority: Option<u8>,
+    wait: Wait,
+) -> Locked<L> {
+    let (state, queued, mask) = (entry.state, entry.queued, entry.mask);
+    let pending = &mut entry.signal;
+    let address = address(state);
+    loop {
+        let signal = match pending.take() {
+            Some(signal) => signal,
+            None => {
+                if queued.count.load(Acquire) == 0
+                    && mask.lock(state, taken, false, Wait::Never).is_ok()
+                {
+                    return Ok(());
+                }
+                if !queued.raised.load(Relaxed) {
+                    // Pairs with the releases, which are sequentially consistent and load the flag afterwards, such
+                    // that a release that skipped `grant` is seen by the attempts below.
+                    queued.raised.store(true, SeqCst);
+                }
+
+                let mut queue = waiters(address);
+                queued.count.fetch_add(1, Relaxed);
+                // Pairs with the fence of `grant` such that either the release is seen here or this waiter is seen by
+                // the release.
+                fence(SeqCst);
+                // A failed attempt may hold some of the words of a mask for a moment, which may have prevented a
+                // grant.
+                grant_locked(&mut queue, state, queued, &mut L::new());
+                let reserved = priority.is_some()
+                    && queue.iter().any(|waiter| {
+                        waiter.address == address
+                            && waiter.priority >= priority
+                            && waiter.indices.iter().any(|&index| mask.has(index))
+                    });
+                if !reserved && mask.lock(state, taken, false, Wait::Never).is_ok() {
+                    queued.count.fetch_sub(1, Relaxed);
+                    return Ok(());
+                } else if let Wait::Never = wait {
+                    queued.count.fetch_sub(1, Relaxed);
+                    return Err(LockError::WouldBlock {
+                        conflicting: mask.conflicts(state),
+                    });
+                }
+
+                let signal = Arc::new(AtomicU8::new(0));
+                let position = queue
+                    .iter()
+                    .rposition(|waiter| waiter.priority >= priority)
+                    .map_or(0, |position| position + 1);
+                queue.insert(
+                    position,
+                    Waiter {
+                        address,
+                        priority,
+                        indices: mask.indices().collect(),
+                        thread: thread::current(),
+                        signal: signal.clone(),
+                    },
+                );
+                signal
+            }
+        };
+        match park(&signal, wait) {
+            GRANTED => {
+                taken.clear();
+                taken.merge(mask);
+                return Ok(());
+            }
+            WOKEN => {}
+            _ => {
+                *pending = Some(signal);
+                return Err(LockError::TimedOut);
+            }
+        }
+    }
+}
+
+/// Parks the thread until `signal` is set or until `wait` expires, in which case it returns `0`.
+fn park(signal: &AtomicU8, wait: Wait) -> u8 {
+    loop {
+        let value = signal.load(Acquire);
+        let now = Instant::now();
+        match wait {
+            _ if value != 0 => break value,
+            Wait::Until(until) if now < until => thread::park_timeout(until - now),
+            Wait::Until(_) | Wait::Never => break 0,
+            Wait::Forever => thread::park(),
+        }
+    }
+}
+
+/// Grants their bits to the waiters of `state` in order. Must be called after bits of `state` are released.
+pub fn grant<L: Lock>(state: &L::State, queued: &Queued) {
+    if !queued.raised.load(SeqCst) {
+        return;
+    }
+    fence(SeqCst);
+    if queued.count.load(Relaxed) > 0 {
+        grant_locked(&mut waiters(address(state)), state, queued, &mut L::new());
+    }
+}
+
+/// Hands the bits of `mask`, which are held by the current thread, over to the waiters of `state` that wait for them,
+/// and releases the other ones. Since the bits are taken on behalf of the waiters before they are released, the
+/// current thread cannot take them back first. Returns the bits that were released.
+pub fn hand_off<L: Lock>(state: &L::State, queued: &Queued, mask: &L) -> L {
+    let mut handed = L::new();
+    handed.merge(mask);
+    let mut queue = waiters(address(state));
+    grant_locked(&mut queue, state, queued, &mut handed);
+    handed.unlock(state, true);
+    grant_locked(&mut queue, state, queued, &mut L::new());
+    handed
+}
+
+/// A waiter with a priority is granted its bits only if they are all free or `handed` and if none of them is reserved
+/// by a waiter ahead of it that is still waiting. A waiter without one is granted the same way if some of its bits are
+/// `handed`, and is otherwise woken once all of its bits are free. The bits that are granted are removed from `handed`.
+fn grant_locked<L: Lock>(
+    queue: &mut Vec<Waiter>,
+    state: &L::State,
+    queued: &Queued,
+    handed: &mut L,
+) {
+    let address = address(state);
+    let mut reserved = L::new();
+    queue.retain(|waiter| {
+        if waiter.address != address {
+            return true;
+        }
+
+        let mut mask = L::new();
+        let mut rest = L::new();
+        for &index in &waiter.indices {
+            mask.add(index);
+            if !handed.has(index) {
+                rest.add(index);
+            }
+        }
+        let signal = if !mask.overlaps(&reserved)
+            && (waiter.priority.is_some() || mask.overlaps(handed))
+            && rest.lock(state, &mut L::new(), false, Wait::Never).is_ok()
+        {
+            for &index in &waiter.indices {
+                handed.remove(index);
+            }
+            GRANTED
+        } else if waiter.priority.is_none() && !mask.is_locked(state, true) {
+            WOKEN
+        } else {
+            if waiter.priority.is_some() {
+                reserved.merge(&mask);
+            }
+            return true;
+        };
+        queued.count.fetch_sub(1, Relaxed);
+        waiter.signal.store(signal, Release);
+        waiter.thread.unpark();
+        false
+    });
+}
+
+#[inline]
+fn address<S>(state: &S) -> usize {
+    state as *const S as usize
+}
+
+/// Locks the queue of the bucket of `address` with a Fibonacci hash, which spreads the aligned addresses evenly.
+#[inline]
+fn waiters(address: usize) -> MutexGuard<'static, Vec<Waiter>> {
+    let hash = address.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize);
+    let bucket = hash >> (usize::BITS - BUCKETS.trailing_zeros());
+    QUEUES[bucket]
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
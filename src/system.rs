@@ -1,61 +1,408 @@
+use crate::lock::Mask;
+use std::{
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering::*},
+        Mutex, MutexGuard, PoisonError,
+    },
+    task::Waker,
+    time::Instant,
+};
+
+static IDENTIFIERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The largest number of words that [`wait_any`] can wait on.
+pub const WAIT_ANY: usize = 128;
+
+/// Futex bitsets only have 32 bits, so the upper half of a mask is folded onto its lower half. Masks that overlap
+/// still overlap once folded, at the cost of some spurious wakes between bits that share a folded position.
+#[inline]
+pub const fn fold(mask: u64) -> u32 {
+    mask as u32 | (mask >> 32) as u32
+}
+
+/// Produces an identifier to register wakers with.
+#[inline]
+pub fn identifier() -> usize {
+    IDENTIFIERS.fetch_add(1, Relaxed)
+}
+
+/// The wakers of the futures that wait for the bits of a multex, with the identifier of their future and the folded
+/// indices they wait for.
+pub struct Wakers {
+    /// The number of registered wakers, such that the releases skip the lock while there is none.
+    registered: AtomicUsize,
+    wakers: Mutex<Vec<(usize, u32, Waker)>>,
+}
+
+impl Wakers {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            registered: AtomicUsize::new(0),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers `waker` such that it is woken by the next [`Wakers::wake`] that overlaps `mask`. The caller must try
+    /// to lock again after this call, since the releases that happened before it are missed.
+    pub fn register<M: Mask>(&self, id: usize, mask: &M, waker: &Waker) {
+        let bits = bits(mask);
+        let mut wakers = self.wakers();
+        match wakers.iter_mut().find(|entry| entry.0 == id) {
+            Some(entry) => {
+                entry.1 = bits;
+                entry.2.clone_from(waker);
+            }
+            None => {
+                wakers.push((id, bits, waker.clone()));
+                self.registered.fetch_add(1, Relaxed);
+            }
+        }
+        drop(wakers);
+        // Pairs with the releases of the bits, which are sequentially consistent, such that either the next attempt of
+        // the caller sees a release or the release sees this waker.
+        fence(SeqCst);
+    }
+
+    /// Forgets the waker registered with `id`.
+    pub fn unregister(&self, id: usize) {
+        let mut wakers = self.wakers();
+        let length = wakers.len();
+        wakers.retain(|entry| entry.0 != id);
+        self.registered.fetch_sub(length - wakers.len(), Relaxed);
+    }
+
+    /// Wakes the futures that wait for the bits of `mask`, which were just released.
+    #[inline]
+    pub fn wake<M: Mask>(&self, mask: &M) {
+        if self.registered.load(SeqCst) > 0 {
+            self.wake_bits(bits(mask));
+        }
+    }
+
+    /// Wakes all the futures. The lock is always taken such that a future that registers concurrently either is woken
+    /// or sees what happened before this call on its next attempt.
+    #[inline]
+    pub fn wake_all(&self) {
+        self.wake_bits(u32::MAX);
+    }
+
+    fn wake_bits(&self, bits: u32) {
+        let mut wakers = self.wakers();
+        let mut woken = Vec::new();
+        let mut index = 0;
+        while index < wakers.len() {
+            if wakers[index].1 & bits == 0 {
+                index += 1;
+            } else {
+                woken.push(wakers.swap_remove(index).2);
+            }
+        }
+        self.registered.fetch_sub(woken.len(), Relaxed);
+        drop(wakers);
+        woken.into_iter().for_each(Waker::wake);
+    }
+
+    #[inline]
+    fn wakers(&self) -> MutexGuard<'_, Vec<(usize, u32, Waker)>> {
+        self.wakers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The folded indices of `mask`.
+#[inline]
+fn bits<M: Mask>(mask: &M) -> u32 {
+    mask.indices()
+        .fold(0, |bits, index| bits | 1 << (index % u32::BITS as usize))
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 #[inline]
-pub fn wait<S, V, M>(state: &S, value: V, mask: M) {
-    use std::{mem::size_of, ptr::null};
+pub fn wait<S, V>(state: &S, value: V, mask: u64, until: Option<Instant>) {
+    use std::mem::{size_of, transmute_copy};
     debug_assert_eq!(size_of::<S>(), size_of::<V>());
-    debug_assert_eq!(size_of::<S>(), size_of::<M>());
+    debug_assert_ne!(mask, 0);
+
+    if size_of::<V>() != size_of::<u64>() {
+        futex_wait(state as *const S as *const u32, value, fold(mask), until);
+        return;
+    }
+    let value = unsafe { transmute_copy::<V, u64>(&value) };
+    let [low, high] = halves(state);
+    let bits = value & mask;
+    match (bits as u32, (bits >> 32) as u32) {
+        (0, 0) => {}
+        (bits, 0) => futex_wait(low, value as u32, bits, until),
+        (0, bits) => futex_wait(high, (value >> 32) as u32, bits, until),
+        _ if waits_any() => {
+            let words = [(low, value as u32), (high, (value >> 32) as u32)];
+            futex_waitv(&words, until);
+        }
+        (bits, _) => {
+            let split = Instant::now() + SPLIT;
+            let until = until.map_or(split, |until| until.min(split));
+            futex_wait(low, value as u32, bits, Some(until));
+        }
+    }
+}
+
+/// How long a wait on both halves of a 64-bit state lasts at most without [`wait_any`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SPLIT: std::time::Duration = std::time::Duration::from_millis(1);
+
+/// The addresses of the low and the high 32-bit halves of a 64-bit `state`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn halves<S>(state: &S) -> [*const u32; 2] {
+    let base = (state as *const S).cast::<u32>();
+    if cfg!(target_endian = "little") {
+        [base, base.wrapping_add(1)]
+    } else {
+        [base.wrapping_add(1), base]
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn futex_wait<V>(address: *const u32, value: V, bits: u32, until: Option<Instant>) {
+    use std::ptr::null;
+
+    // `FUTEX_WAIT_BITSET` expects an absolute time on the monotonic clock.
+    let timeout = until.map(deadline);
     unsafe {
         libc::syscall(
             libc::SYS_futex,
-            state as *const _,
+            address,
             libc::FUTEX_WAIT_BITSET | libc::FUTEX_PRIVATE_FLAG,
             value,
+            timeout
+                .as_ref()
+                .map_or(null(), |timeout| timeout as *const libc::timespec),
+            null::<u32>(),
+            bits,
+        )
+    };
+}
+
+/// Returns `true` if [`wait_any`] can wait on several words at once. Since its waiters do not wait for a bitset, the
+/// threads that change these words must wake them with [`wake`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn waits_any() -> bool {
+    use std::{io::Error, ptr::null, sync::atomic::AtomicU8};
+    const UNKNOWN: u8 = 0;
+    const SUPPORTED: u8 = 1;
+    const UNSUPPORTED: u8 = 2;
+    static WAITV: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+    // Miri does not know the system call.
+    if cfg!(miri) {
+        return false;
+    }
+    match WAITV.load(Relaxed) {
+        UNKNOWN => {
+            // Linux knows `futex_waitv` since 5.16 and then rejects an empty list of words. Every thread that asks
+            // gets the same answer, such that it is fine to ask more than once.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_futex_waitv,
+                    null::<u8>(),
+                    0u32,
+                    0u32,
+                    null::<libc::timespec>(),
+                    libc::CLOCK_MONOTONIC,
+                )
+            };
+            let supported =
+                result == -1 && Error::last_os_error().raw_os_error() == Some(libc::EINVAL);
+            WAITV.store(if supported { SUPPORTED } else { UNSUPPORTED }, Relaxed);
+            supported
+        }
+        value => value == SUPPORTED,
+    }
+}
+
+/// Waits for one of the 32-bit `words` of memory to change from its paired value. Must only be called if [`waits_any`]
+/// holds and with at most [`WAIT_ANY`] words.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn wait_any(words: &[(*const u32, u32)], until: Option<Instant>) {
+    futex_waitv(words, until);
+}
+
+/// Wakes the threads of [`wait_any`] that wait on the 32-bit word of memory at `address`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn wake_word(address: *const u32) {
+    futex_wake(address, u32::MAX);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn futex_waitv(words: &[(*const u32, u32)], until: Option<Instant>) {
+    use std::ptr::null;
+
+    /// The `struct futex_waitv` of the kernel.
+    #[repr(C)]
+    struct Waiter {
+        value: u64,
+        address: u64,
+        flags: u32,
+        reserved: u32,
+    }
+    const FUTEX2_SIZE_U32: u32 = 0x02;
+
+    debug_assert!(words.len() <= WAIT_ANY);
+    let waiters: Vec<_> = words
+        .iter()
+        .map(|&(word, value)| Waiter {
+            value: value as u64,
+            address: word as u64,
+            flags: FUTEX2_SIZE_U32 | libc::FUTEX_PRIVATE_FLAG as u32,
+            reserved: 0,
+        })
+        .collect();
+    let timeout = until.map(deadline);
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex_waitv,
+            waiters.as_ptr(),
+            waiters.len() as u32,
+            0u32,
+            timeout
+                .as_ref()
+                .map_or(null(), |timeout| timeout as *const libc::timespec),
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+}
+
+/// Turns `until` into an absolute time on the monotonic clock.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn deadline(until: Instant) -> libc::timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let remain = until.saturating_duration_since(Instant::now());
+    let nanos = now.tv_nsec as u64 + remain.subsec_nanos() as u64;
+    libc::timespec {
+        tv_sec: now
+            .tv_sec
+            .saturating_add(remain.as_secs().try_into().unwrap_or(libc::time_t::MAX))
+            .saturating_add((nanos / 1_000_000_000) as _),
+        tv_nsec: (nanos % 1_000_000_000) as _,
+    }
+}
+
+/// Wakes the threads that wait for the bits of `mask` in `state`, through the halves of a 64-bit state that hold them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn wake<S>(state: &S, mask: u64) {
+    use std::mem::size_of;
+    debug_assert_ne!(mask, 0);
+    if size_of::<S>() != size_of::<u64>() {
+        futex_wake(state as *const S as *const u32, fold(mask));
+        return;
+    }
+    let [low, high] = halves(state);
+    for (address, bits) in [(low, mask as u32), (high, (mask >> 32) as u32)] {
+        if bits != 0 {
+            futex_wake(address, bits);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn futex_wake(address: *const u32, bits: u32) {
+    use std::ptr::null;
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            address,
+            libc::FUTEX_WAKE_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            i32::MAX,
             null::<libc::timespec>(),
             null::<u32>(),
-            // TODO: Convert mask to u32. For u64, bit-or the bottom 32 bits with the top 32 bits.
-            mask,
+            bits,
         )
     };
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 #[inline]
-pub fn wake<S, M>(state: &S, mask: M) {
+pub fn wake_one<S>(state: &S) {
     use std::ptr::null;
     unsafe {
         libc::syscall(
             libc::SYS_futex,
             state as *const _,
-            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
-            u32::MAX,
+            libc::FUTEX_WAKE_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            1,
             null::<libc::timespec>(),
             null::<u32>(),
-            // TODO: Convert mask to u32. For u64, bit or the bottom 32 bits with the top 32 bits.
-            mask,
+            u32::MAX,
         )
     };
 }
 
 #[cfg(target_os = "windows")]
 #[inline]
-pub fn wait<S, V, M>(state: &S, value: V, _: M) {
+pub fn wait<S, V>(state: &S, value: V, _: u64, until: Option<Instant>) {
     use std::mem::size_of;
     debug_assert_eq!(size_of::<S>(), size_of::<V>());
 
+    // `u32::MAX` means an infinite timeout, so finite timeouts are kept below it.
+    let timeout = until.map_or(u32::MAX, |until| {
+        let remain = until
+            .saturating_duration_since(Instant::now())
+            .as_nanos()
+            .div_ceil(1_000_000);
+        remain.min(u32::MAX as u128 - 1) as u32
+    });
     unsafe {
         windows_sys::Win32::System::Threading::WaitOnAddress(
             state as *const _ as *const _,
             &value as *const _ as *const _,
             size_of::<S>(),
-            u32::MAX,
+            timeout,
         )
     };
 }
 
 #[cfg(target_os = "windows")]
 #[inline]
-pub fn wake<S, M>(state: &S, _: M) {
+pub fn wake<S>(state: &S, _: u64) {
     unsafe {
         windows_sys::Win32::System::Threading::WakeByAddressAll(state as *const _ as *const _)
     };
 }
+
+#[cfg(target_os = "windows")]
+#[inline]
+pub fn wake_one<S>(state: &S) {
+    unsafe {
+        windows_sys::Win32::System::Threading::WakeByAddressSingle(state as *const _ as *const _)
+    };
+}
+
+/// `WaitOnAddress` waits on a single address.
+#[cfg(target_os = "windows")]
+#[inline]
+pub fn waits_any() -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+#[inline]
+pub fn wait_any(_: &[(*const u32, u32)], _: Option<Instant>) {
+    unreachable!("`waits_any` never holds")
+}
+
+#[cfg(target_os = "windows")]
+#[inline]
+pub fn wake_word(_: *const u32) {
+    unreachable!("`waits_any` never holds")
+}
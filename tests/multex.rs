@@ -1,12 +1,40 @@
-use multex::*;
-use std::result;
+use multex::{lock::Wait, *};
+use std::{
+    future::Future,
+    pin::pin,
+    result,
+    sync::{Arc, Barrier},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
 
 type Result = result::Result<(), Box<dyn std::error::Error>>;
 
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => break output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
 #[test]
 fn cannot_lock_the_same_index_twice() {
-    if let Err(error) = Key::<usize, _>::new([0, 1, 0]) {
-        assert_eq!(error.index, 0);
+    if let Err(LockError::InvalidIndex { index }) = Key::<usize, _>::new([0, 1, 0]) {
+        assert_eq!(index, 0);
     } else {
         panic!();
     }
@@ -18,7 +46,7 @@ fn can_lock_lots_of_indices() -> Result {
     let multex = MultexV::new(&mut items);
     let indices = (0..1000).collect::<Vec<usize>>();
     let mut key = Key::new(indices.as_slice())?;
-    let guard = multex.lock_with(&mut key, false);
+    let guard = multex.lock_with(&mut key, false).unwrap();
     for (mut i, item) in guard.iter().enumerate() {
         assert_eq!(item, &Some(&mut i));
     }
@@ -30,8 +58,8 @@ fn locks_different_indices() -> Result {
     let multex = Multex8::new([1u8, 2u8, 3u8, 4u8]);
     let mut key1 = Key::new([0])?;
     let mut key2 = Key::new((1,))?;
-    let mut guard1 = multex.lock_with(&mut key1, false);
-    let mut guard2 = multex.lock_with(&mut key2, false);
+    let mut guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let mut guard2 = multex.lock_with(&mut key2, false).unwrap();
     let [Some(value1)] = guard1.as_mut() else {
         panic!()
     };
@@ -45,7 +73,7 @@ fn locks_different_indices() -> Result {
 
 #[test]
 fn locks_all_without_panic() {
-    Multex32::new(Vec::new()).lock().push(1);
+    Multex32::new(Vec::new()).lock().unwrap().push(1);
 }
 
 #[test]
@@ -60,6 +88,898 @@ fn locks_with_unit() -> Result {
     Ok(())
 }
 
+#[test]
+fn reads_the_same_index_concurrently() -> Result {
+    let multex = RwMultex64::new([1u8, 2u8, 3u8]);
+    let mut key1 = Key::new([0, 1])?;
+    let mut key2 = Key::new([1, 2])?;
+    let mut key3 = Key::new(1)?;
+    let guard1 = multex.read_with(&mut key1);
+    let guard2 = multex.try_read_with(&mut key2);
+    assert!(multex.try_write_with(&mut key3).is_none());
+    let Some(guard2) = guard2 else { panic!() };
+    assert_eq!(*guard1, [Some(&1u8), Some(&2u8)]);
+    assert_eq!(*guard2, [Some(&2u8), Some(&3u8)]);
+    Ok(())
+}
+
+#[test]
+fn writes_exclude_reads() -> Result {
+    let multex = RwMultexV::new(vec![1u8, 2u8, 3u8]);
+    let mut key1 = Key::new([2, 0])?;
+    let mut key2 = Key::new([0])?;
+    let mut key3 = Key::new([1])?;
+    let mut guard1 = multex.write_with(&mut key1);
+    assert!(multex.try_read_with(&mut key2).is_none());
+    assert!(multex.try_read_with(&mut key3).is_some());
+    **guard1[0].as_mut().unwrap() += 1;
+    drop(guard1);
+    assert_eq!(multex.into_inner(), [1u8, 2u8, 4u8]);
+    Ok(())
+}
+
+#[test]
+fn upgrades_without_releasing() -> Result {
+    let multex = RwMultex8::new([1u8, 2u8, 3u8]);
+    let mut key1 = Key::new([0, 1])?;
+    let mut key2 = Key::new([1])?;
+    let mut key3 = Key::new([1, 2])?;
+    let guard1 = multex.upgradable_read_with(&mut key1);
+    assert!(multex.try_upgradable_read_with(&mut key3).is_none());
+    let guard2 = multex.read_with(&mut key2);
+    let Err(guard1) = UpgradableGuard::try_upgrade(guard1) else {
+        panic!()
+    };
+    drop(guard2);
+    let mut guard1 = UpgradableGuard::upgrade(guard1);
+    assert_eq!(*guard1.mask(), 0b11);
+    **guard1[1].as_mut().unwrap() += 1;
+    let guard1 = UpgradedGuard::downgrade(guard1);
+    assert_eq!(*guard1, [Some(&1u8), Some(&3u8)]);
+    assert!(multex.try_read_with(&mut key2).is_some());
+    Ok(())
+}
+
+#[test]
+fn times_out_and_releases_acquired_words() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([9])?;
+    let mut key2 = Key::new([1, 9])?;
+    let mut key3 = Key::new([1])?;
+    let _guard1 = multex.lock_with(&mut key1, false).unwrap();
+    assert!(multex.lock_for(Duration::from_millis(10)).is_none());
+    assert!(multex
+        .lock_with_for(&mut key2, false, Duration::from_millis(10))
+        .is_none());
+    assert!(!multex.is_locked_with(&key3, true));
+    assert!(multex.try_lock_with(&mut key3, false).is_some());
+    Ok(())
+}
+
+#[test]
+fn wakes_waiters_of_folded_bits() -> Result {
+    let multex = Multex64::new([0usize; 64]);
+    let barrier = Barrier::new(3);
+    let mut key1 = Key::new([8, 40])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        // Both indices fold onto the same bit of the futex bitset, such that releasing them must wake both threads.
+        let handles = [8, 40].map(|index| {
+            let (multex, barrier) = (&multex, &barrier);
+            scope.spawn(move || {
+                let mut key = Key::new([index]).unwrap();
+                assert!(multex.try_lock_with(&mut key, false).is_none());
+                barrier.wait();
+                let mut guard = multex
+                    .lock_with_for(&mut key, false, Duration::from_secs(10))
+                    .unwrap()
+                    .unwrap();
+                **guard[0].as_mut().unwrap() += 1;
+            })
+        });
+        barrier.wait();
+        drop(guard1);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+    let values = multex.into_inner();
+    assert_eq!((values[8], values[40]), (1, 1));
+    Ok(())
+}
+
+#[test]
+fn wakes_waiters_of_high_bits() {
+    // Only the high half of the state changes, which the waiters must still notice.
+    let multex = Multex64::new([0usize; 64]);
+    thread::scope(|scope| {
+        for _ in 0..2 {
+            scope.spawn(|| {
+                let mut key = Key::new([40]).unwrap();
+                for _ in 0..1000 {
+                    let mut guard = multex
+                        .lock_with_for(&mut key, false, Duration::from_secs(10))
+                        .unwrap()
+                        .unwrap();
+                    **guard[0].as_mut().unwrap() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(multex.into_inner()[40], 2000);
+}
+
+#[test]
+fn waits_on_the_conflicting_words() -> Result {
+    let multex = Multex32A::<_, 40>::new([0u8; 1280]);
+    let mut key1 = Key::new([5, 1061])?;
+    let mut key2 = Key::new([37, 1061])?;
+    let mut key3 = Key::new([5, 37])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    assert!(multex
+        .lock_with_for(&mut key2, false, Duration::from_millis(10))
+        .is_none());
+    thread::scope(|scope| {
+        let handle1 = scope.spawn(|| multex.lock_with(&mut key2, false).unwrap().mask()[33]);
+        let handle2 = scope.spawn(|| multex.lock_some_with(&mut key3, 2, 2).unwrap().mask()[0]);
+        let handle3 = scope.spawn(|| multex.lock_any_with(&Key::new([5]).unwrap()).unwrap().0);
+        thread::sleep(Duration::from_millis(10));
+        drop(guard1);
+        assert_eq!(handle1.join().unwrap(), 1 << 5);
+        assert_eq!(handle2.join().unwrap(), 1 << 5);
+        assert_eq!(handle3.join().unwrap(), 5);
+    });
+
+    let multex = Multex32V::new(vec![0u8; 1280]);
+    let mut key1 = Key::new([1061])?;
+    let mut key2 = Key::new([5, 1061])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            **multex.lock_with(&mut key2, false).unwrap()[1]
+                .as_mut()
+                .unwrap() += 1
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(guard1);
+        handle.join().unwrap();
+    });
+    assert_eq!(multex.into_inner()[1061], 1);
+    Ok(())
+}
+
+#[test]
+fn wakes_the_words_separately() -> Result {
+    // The waiters wait on the high halves of different words, such that each one is only woken by its own word.
+    let multex = MultexA::<_, 2>::with_strategy([0u8; 128], Strategy::Ordered);
+    let (mut key1, mut key2) = (Key::new([40])?, Key::new([100])?);
+    let (mut key3, mut key4) = (Key::new([40])?, Key::new([100])?);
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let guard2 = multex.lock_with(&mut key2, false).unwrap();
+    thread::scope(|scope| {
+        let handle1 = scope.spawn(|| multex.lock_with(&mut key3, false).unwrap().mask()[0]);
+        let handle2 = scope.spawn(|| multex.lock_with(&mut key4, false).unwrap().mask()[1]);
+        drop(guard2);
+        assert_eq!(handle2.join().unwrap(), 1 << 36);
+        assert!(!handle1.is_finished());
+        drop(guard1);
+        assert_eq!(handle1.join().unwrap(), 1 << 40);
+    });
+
+    let multex = MultexV::new(vec![0u8; 128]);
+    let (key1, key2) = (Key::new([40])?, Key::new([100])?);
+    let guard1 = multex.lock_any_with(&key1).unwrap();
+    let guard2 = multex.lock_any_with(&key2).unwrap();
+    thread::scope(|scope| {
+        let handle1 = scope.spawn(|| multex.lock_any_with(&key1).unwrap().0);
+        let handle2 = scope.spawn(|| multex.lock_any_with(&key2).unwrap().0);
+        drop(guard2);
+        assert_eq!(handle2.join().unwrap(), 100);
+        assert!(!handle1.is_finished());
+        drop(guard1);
+        assert_eq!(handle1.join().unwrap(), 40);
+    });
+    Ok(())
+}
+
+#[test]
+fn holds_earlier_words_while_waiting_in_order() -> Result {
+    let multex = Multex8A::<_, 4>::with_strategy([0u32; 32], Strategy::Ordered);
+    let mut key1 = Key::new([20])?;
+    let mut key2 = Key::new([3, 20])?;
+    let key3 = Key::new([3])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    assert!(multex
+        .lock_with_for(&mut key2, false, Duration::from_millis(10))
+        .is_none());
+    assert!(!multex.is_locked_with(&key3, true));
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| multex.lock_with(&mut key2, false).unwrap().mask()[0]);
+        let until = Instant::now() + Duration::from_secs(10);
+        while !multex.is_locked_with(&key3, true) {
+            assert!(Instant::now() < until, "the first word is never held");
+            thread::yield_now();
+        }
+        drop(guard1);
+        assert_eq!(handle.join().unwrap(), 1 << 3);
+    });
+
+    thread::scope(|scope| {
+        for index in 0..4 {
+            let multex = &multex;
+            scope.spawn(move || {
+                let mut key = Key::new([index, 31 - index, 15, 16]).unwrap();
+                for _ in 0..1000 {
+                    **multex.lock_with(&mut key, false).unwrap()[2]
+                        .as_mut()
+                        .unwrap() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(multex.into_inner()[15], 4000);
+    Ok(())
+}
+
+#[test]
+fn times_out_when_locking_words_held_by_an_ordered_waiter() -> Result {
+    // A thread that holds a later word and then locks an earlier one waits for a thread that holds the earlier word
+    // while it waits for the later one, which only a timeout ends.
+    let multex = Multex8A::<_, 4>::with_strategy([0u32; 32], Strategy::Ordered);
+    let mut key1 = Key::new([20])?;
+    let mut key2 = Key::new([3, 20])?;
+    let mut key3 = Key::new([3])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| multex.lock_with(&mut key2, false).unwrap().mask()[0]);
+        let until = Instant::now() + Duration::from_secs(10);
+        while !multex.is_locked_with(&key3, true) {
+            assert!(Instant::now() < until, "the first word is never held");
+            thread::yield_now();
+        }
+        assert!(multex
+            .lock_with_for(&mut key3, false, Duration::from_millis(10))
+            .is_none());
+        drop(guard1);
+        assert_eq!(handle.join().unwrap(), 1 << 3);
+    });
+    Ok(())
+}
+
+#[test]
+fn adds_indices_to_a_guard() -> Result {
+    let multex = Multex16::new([1u8, 2u8, 3u8, 4u8]);
+    let mut key1 = Key::new([0])?;
+    let mut key2 = Key::new([1])?;
+    let mut key3 = Key::new([2, 3])?;
+    let mut key4 = Key::new([3])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let guard1 = Guard::try_lock_with(guard1, &multex, &mut key2, false)
+        .ok()
+        .unwrap()
+        .unwrap();
+    assert_eq!(*guard1.mask(), 0b11);
+    let guard4 = multex.lock_with(&mut key4, false).unwrap();
+    let Err(guard1) = Guard::try_lock_with(guard1, &multex, &mut key3, false) else {
+        panic!()
+    };
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(guard4);
+        });
+        let mut guard1 = Guard::lock_with(guard1, &multex, &mut key3, false).unwrap();
+        assert_eq!(*guard1.mask(), 0b1111);
+        let (([Some(value1)], [Some(value2)]), [Some(value3), Some(value4)]) = &mut *guard1 else {
+            panic!()
+        };
+        **value1 += **value2 + **value3 + **value4;
+    });
+    assert_eq!(multex.into_inner(), [10u8, 2u8, 3u8, 4u8]);
+    Ok(())
+}
+
+#[test]
+fn poisons_the_indices_of_a_panicking_guard() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([1, 9])?;
+    let mut key2 = Key::new([9])?;
+    let mut key3 = Key::new([0, 8])?;
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            let mut guard = multex.lock_with(&mut key1, false).unwrap();
+            **guard[1].as_mut().unwrap() = 1;
+            panic!();
+        });
+        assert!(handle.join().is_err());
+    });
+    assert!(multex.is_poisoned_with(&key2, false));
+    assert!(!multex.is_poisoned_with(&key3, true));
+    assert!(multex.lock_with(&mut key3, false).is_ok());
+    let Err(error) = multex.lock_with(&mut key2, false) else {
+        panic!()
+    };
+    assert_eq!(*error.into_inner()[0].as_deref().unwrap(), 1);
+    multex.clear_poison_with(&key2);
+    assert!(multex.lock_with(&mut key2, false).is_ok());
+    assert!(multex.lock_with(&mut key1, false).is_err());
+    Ok(())
+}
+
+#[test]
+fn does_not_poison_the_guards_taken_while_panicking() {
+    struct Relock<'a>(&'a Multex8<[u8; 2]>);
+
+    impl Drop for Relock<'_> {
+        fn drop(&mut self) {
+            let mut key = Key::new([1]).unwrap();
+            let _guard = self.0.lock_with(&mut key, false).unwrap();
+        }
+    }
+
+    let multex = Multex8::new([0u8; 2]);
+    let result = thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let _relock = Relock(&multex);
+                panic!();
+            })
+            .join()
+    });
+    assert!(result.is_err());
+    assert!(!multex.is_poisoned(true));
+}
+
+#[test]
+fn locks_asynchronously_once_bits_are_released() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([9])?;
+    let mut key2 = Key::new([1, 9])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            let mut guard = block_on(multex.lock_with_async(&mut key2, false)).unwrap();
+            **guard[1].as_mut().unwrap() += 1;
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(guard1);
+        handle.join().unwrap();
+    });
+    assert_eq!(block_on(multex.lock_async()).unwrap()[9], 1);
+    Ok(())
+}
+
+#[test]
+fn drops_pending_futures_without_holding_bits() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([9])?;
+    let mut key2 = Key::new([1, 9])?;
+    let mut key3 = Key::new([1])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let unpark = Arc::new(Unpark(thread::current()));
+    let waker = Waker::from(unpark.clone());
+    let mut future = Box::pin(multex.lock_with_async(&mut key2, false));
+    assert!(future
+        .as_mut()
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending());
+    assert_eq!(Arc::strong_count(&unpark), 3);
+    drop(future);
+    assert_eq!(Arc::strong_count(&unpark), 2);
+    assert!(!multex.is_locked_with(&key3, true));
+    assert!(multex.try_lock_with(&mut key3, false).is_some());
+    drop(guard1);
+    assert!(!multex.is_locked_with(&key2, true));
+    Ok(())
+}
+
+#[test]
+#[should_panic]
+fn panics_when_polling_a_completed_future() {
+    let multex = Multex8::new([0u8; 2]);
+    let mut future = pin!(multex.lock_async());
+    let mut context = Context::from_waker(Waker::noop());
+    let Poll::Ready(guard) = future.as_mut().poll(&mut context) else {
+        panic!()
+    };
+    drop(guard);
+    let _ = future.as_mut().poll(&mut context);
+}
+
+#[test]
+fn locks_the_whole_value_of_an_arc() {
+    let multex = Arc::new(Multex8::new([0u8; 4]));
+    let mut guard = multex.lock_arc().unwrap();
+    assert!(multex.try_lock().is_none());
+    thread::spawn(move || guard[3] += 1).join().unwrap();
+    assert_eq!(multex.lock().unwrap()[3], 1);
+}
+
+#[test]
+fn sends_owned_guards_to_other_threads() -> Result {
+    let multex = Arc::new(MultexV::new(vec![1u8; 100]));
+    let key = Key::new(vec![3, 70])?;
+    let mut guard = multex.lock_with_arc(key, false).unwrap();
+    assert!(multex.try_lock_with(&mut Key::new([70])?, false).is_none());
+    let key = thread::spawn(move || {
+        for value in guard.get().into_iter().flatten() {
+            *value += 1;
+        }
+        OwnedGuard::into_key(guard)
+    })
+    .join()
+    .unwrap();
+    let guard = multex.lock_with_arc(key, false).unwrap();
+    assert_eq!(guard.read(), [Some(&2u8), Some(&2u8)]);
+    drop(guard);
+    let Ok(multex) = Arc::try_unwrap(multex) else {
+        panic!()
+    };
+    assert_eq!(multex.into_inner()[..4], [1u8, 1u8, 1u8, 2u8]);
+    Ok(())
+}
+
+#[test]
+fn waits_on_the_indices_of_a_guard() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let condvar = MultexCondvar::new();
+    let mut key1 = Key::new([3, 12])?;
+    let mut key2 = Key::new([12])?;
+    let mut key3 = Key::new([3])?;
+    thread::scope(|scope| {
+        let guard1 = multex.lock_with(&mut key1, false).unwrap();
+        scope.spawn(|| {
+            let mut guard2 = multex.lock_with(&mut key2, false).unwrap();
+            **guard2[0].as_mut().unwrap() = 5;
+            condvar.notify_all();
+        });
+        let guard1 = condvar
+            .wait_while(guard1, |items| **items[1].as_mut().unwrap() == 0)
+            .unwrap();
+        assert_eq!(*guard1.mask(), [0b1000, 0b10000]);
+        assert!(multex.try_lock_with(&mut key3, false).is_none());
+    });
+    assert_eq!(multex.into_inner()[12], 5);
+    Ok(())
+}
+
+#[test]
+fn splits_and_merges_guards() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([2, 9, 5])?;
+    let mut key2 = Key::new([9])?;
+    let key3 = Key::new([2, 5])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let mut guards = Guard::split(guard1);
+    let guard3 = guards.pop().unwrap();
+    let guard2 = guards.pop().unwrap();
+    let guard1 = guards.pop().unwrap();
+    drop(guards);
+    assert_eq!(*guard2.mask(), [0, 0b10]);
+    drop(guard2);
+    assert!(multex.try_lock_with(&mut key2, false).is_some());
+    let mut guard = Guard::merge(guard1, guard3);
+    assert_eq!(*guard.mask(), [0b100100, 0]);
+    **guard.1.as_mut().unwrap() += 1;
+    assert!(multex.is_locked_with(&key3, false));
+    drop(guard);
+    assert!(!multex.is_locked_with(&key3, true));
+    assert_eq!(multex.into_inner()[5], 1);
+    Ok(())
+}
+
+#[test]
+fn releases_indices_early() -> Result {
+    let multex = MultexV::new(vec![0u8; 100]);
+    let mut key1 = Key::new(vec![4, 80, 33, 7])?;
+    let mut key2 = Key::new([33, 80])?;
+    let mut key3 = Key::new([4])?;
+    let mut guard1 = multex.lock_with(&mut key1, false).unwrap();
+    Guard::release(&mut guard1, [80]);
+    Guard::retain(&mut guard1, |index, _| index != 33);
+    assert!(guard1[1].is_none() && guard1[2].is_none());
+    assert!(multex.try_lock_with(&mut key2, false).is_some());
+    assert!(multex.try_lock_with(&mut key3, false).is_none());
+    **guard1[3].as_mut().unwrap() += 1;
+    Ok(())
+}
+
+#[test]
+fn unlocks_a_guard_temporarily() -> Result {
+    let multex = Multex16::new([0u8; 16]);
+    let mut key1 = Key::new([1, 2])?;
+    let mut key2 = Key::new([2])?;
+    let mut guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let locked = Guard::unlocked(&mut guard1, || {
+        let mut guard2 = multex.lock_with(&mut key2, false).unwrap();
+        **guard2[0].as_mut().unwrap() += 1;
+        drop(guard2);
+        multex.is_locked(true)
+    });
+    assert!(!locked);
+    assert_eq!(*guard1.mask(), 0b110);
+    assert_eq!(**guard1[1].as_mut().unwrap(), 1);
+    Ok(())
+}
+
+#[test]
+fn reports_conflicting_indices() -> Result {
+    let multex = Multex8::new([0u8; 8]);
+    let mut key1 = Key::new([1, 2])?;
+    let mut key2 = Key::new([2, 3])?;
+    let guard = multex.lock_with(&mut key1, false).unwrap();
+    let Err(LockError::WouldBlock { conflicting }) = multex.try_lock_with_report(&mut key2) else {
+        panic!()
+    };
+    assert_eq!(conflicting, 0b100);
+    drop(guard);
+    assert!(multex.try_lock_with_report(&mut key2).is_ok());
+    Ok(())
+}
+
+#[test]
+fn reports_conflicting_read_indices_and_empty_keys() -> Result {
+    let multex = RwMultex8::new([0u8; 8]);
+    let mut key1 = Key::new([1, 2])?;
+    let mut key2 = Key::new([2, 3])?;
+    let _guard = multex.read_with(&mut key1);
+    let Err(LockError::WouldBlock { conflicting }) =
+        multex.acquire_write_with(&mut key2, Wait::Never)
+    else {
+        panic!()
+    };
+    assert_eq!(conflicting, 0b100);
+    assert!(multex.acquire_read_with(&mut key2, Wait::Never).is_ok());
+    let multex = Multex8::new([0u8; 8]);
+    let key3 = Key::<u8, [usize; 0]>::new([])?;
+    let Err(LockError::EmptyKey) = multex.acquire_any_with(&key3, Wait::Never) else {
+        panic!()
+    };
+    Ok(())
+}
+
+#[test]
+fn reports_why_locks_fail() -> Result {
+    let multex = Arc::new(Multex8::new([0u8; 8]));
+    let mut key1 = Key::new([1])?;
+    let Ok(guard) = multex.acquire(Wait::Never) else {
+        panic!()
+    };
+    let Err(LockError::TimedOut) =
+        multex.acquire_with(&mut key1, false, Wait::after(Duration::from_millis(1)))
+    else {
+        panic!()
+    };
+    drop(guard);
+
+    let handle = thread::spawn({
+        let multex = multex.clone();
+        move || {
+            let _guard = multex.lock();
+            panic!()
+        }
+    });
+    assert!(handle.join().is_err());
+    let Err(LockError::Poisoned(mut guard)) = multex.acquire_with(&mut key1, false, Wait::Forever)
+    else {
+        panic!()
+    };
+    **guard[0].as_mut().unwrap() += 1;
+    Ok(())
+}
+
+#[test]
+fn cancels_pending_locks_when_closed() -> Result {
+    let multex = Multex8::new([0u8; 8]);
+    let mut key1 = Key::new([1])?;
+    let mut key2 = Key::new([1])?;
+    let mut key3 = Key::new([1])?;
+    let guard = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            let locked = multex.acquire_with(&mut key2, false, Wait::Forever);
+            matches!(locked, Err(LockError::Cancelled))
+        });
+        let mut future = pin!(multex.acquire_with_async(&mut key3, false));
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(future.as_mut().poll(&mut context).is_pending());
+        while multex.queued() == 0 {
+            thread::yield_now();
+        }
+        multex.close();
+        let Poll::Ready(Err(LockError::Cancelled)) = future.as_mut().poll(&mut context) else {
+            panic!()
+        };
+        drop(guard);
+        assert!(handle.join().unwrap());
+    });
+    assert!(!multex.is_locked(true));
+    Ok(())
+}
+
+#[test]
+fn refuses_locks_on_a_closed_multex() -> Result {
+    let multex = Arc::new(Multex8::new([0u8; 8]));
+    let other = Multex8::new([0u8; 8]);
+    let mut key1 = Key::new([1])?;
+    let mut key2 = Key::new([2])?;
+    let mut key3 = Key::new([3])?;
+    let mut key4 = Key::new([4])?;
+    let guard = multex.lock_with(&mut key1, false).unwrap();
+    multex.close();
+    assert!(multex.is_closed());
+    let Err(LockError::Closed) = Guard::acquire_with(guard, &*multex, &mut key2, false) else {
+        panic!()
+    };
+    let Err(LockError::Closed) = multex.acquire_with_arc(Key::new([5])?, false, Wait::Forever)
+    else {
+        panic!()
+    };
+    let Err(LockError::Closed) = acquire_many(((&other, &mut key3), (&*multex, &mut key4))) else {
+        panic!()
+    };
+    let Err(LockError::Closed) = block_on(multex.acquire_async()) else {
+        panic!()
+    };
+    assert!(!multex.is_locked(true));
+    assert!(!other.is_locked(true));
+    Ok(())
+}
+
+#[test]
+fn claims_any_free_index() -> Result {
+    let multex = Multex64A::<_, 2>::new([0u8; 128]);
+    let key = Key::new([3, 70, 100])?;
+    let mut guards = Vec::new();
+    for _ in 0..3 {
+        let (index, mut guard) = multex.lock_any_with(&key).unwrap();
+        **(*guard).as_mut().unwrap() += 1;
+        guards.push((index, guard));
+    }
+    let mut indices = guards.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    indices.sort();
+    assert_eq!(indices, [3, 70, 100]);
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| multex.lock_any_with(&key).unwrap().0);
+        let (index, _) = guards.remove(1);
+        assert_eq!(handle.join().unwrap(), index);
+    });
+    Ok(())
+}
+
+#[test]
+fn takes_only_free_indices_partially() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([0, 3, 9])?;
+    let mut key2 = Key::new([0, 1, 2, 9, 10])?;
+    let _guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let guard2 = multex.lock_with(&mut key2, true).unwrap();
+    assert_eq!(*guard2.mask(), [0b110, 0b100]);
+    Ok(())
+}
+
+#[test]
+fn waits_for_a_minimum_of_indices() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let mut key1 = Key::new([6, 7, 8])?;
+    let mut key2 = Key::new([5, 6, 7, 8, 9])?;
+    let guard1 = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| *multex.lock_some_with(&mut key2, 3, 4).unwrap().mask());
+        thread::sleep(Duration::from_millis(10));
+        assert!(!handle.is_finished());
+        drop(guard1);
+        assert_eq!(handle.join().unwrap(), [0b1110_0000, 0b1]);
+    });
+    let mut key3 = Key::new([5, 6, 7])?;
+    let guard3 = multex.lock_some_with(&mut key3, 0, 2).unwrap();
+    assert_eq!(*guard3.mask(), [0b0110_0000, 0]);
+    Ok(())
+}
+
+#[test]
+fn locks_keys_of_several_multexes() -> Result {
+    let entities = Multex64::new([0u64; 64]);
+    let components = Multex8V::new(vec![0u32; 16]);
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let mut key1 = Key::new([1, 2]).unwrap();
+                let mut key2 = Key::new([10]).unwrap();
+                for _ in 0..1000 {
+                    let (mut guard1, mut guard2) =
+                        lock_many(((&entities, &mut key1), (&components, &mut key2))).unwrap();
+                    **guard1[0].as_mut().unwrap() += 1;
+                    **guard2[0].as_mut().unwrap() += 1;
+                }
+            });
+            scope.spawn(|| {
+                let mut key1 = Key::new([10, 11]).unwrap();
+                let mut key2 = Key::new([2]).unwrap();
+                for _ in 0..1000 {
+                    let (mut guard1, mut guard2) =
+                        lock_many(((&components, &mut key1), (&entities, &mut key2))).unwrap();
+                    **guard1[0].as_mut().unwrap() += 1;
+                    **guard2[0].as_mut().unwrap() += 1;
+                }
+            });
+        }
+    });
+    let mut key1 = Key::new([1, 2])?;
+    let mut key2 = Key::new([10, 11])?;
+    let (guard1, guard2) = lock_many(((&entities, &mut key1), (&components, &mut key2))).unwrap();
+    assert_eq!(
+        (guard1[0].as_deref(), guard1[1].as_deref()),
+        (Some(&4000), Some(&4000))
+    );
+    assert_eq!(
+        (guard2[0].as_deref(), guard2[1].as_deref()),
+        (Some(&8000), Some(&0))
+    );
+    Ok(())
+}
+
+#[test]
+fn hands_bits_to_waiting_threads() -> Result {
+    let multex = Multex16::new([0u8; 16]);
+    let mut key1 = Key::new([0])?;
+    let mut key2 = Key::new([0])?;
+    let mut guard = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            **multex.lock_with(&mut key2, false).unwrap()[0]
+                .as_mut()
+                .unwrap() += 1
+        });
+        while **guard[0].as_mut().unwrap() == 0 {
+            Guard::bump(&mut guard);
+        }
+        Guard::unlock_fair(guard);
+    });
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            **multex.lock_with(&mut key2, false).unwrap()[0]
+                .as_mut()
+                .unwrap() += 1
+        });
+        loop {
+            let mut guard = multex.lock_with(&mut key1, false).unwrap();
+            if **guard[0].as_mut().unwrap() == 2 {
+                break;
+            }
+            Guard::unlock_fair(guard);
+        }
+    });
+    assert!(!multex.is_locked(true));
+    Ok(())
+}
+
+#[test]
+#[should_panic]
+fn panics_when_locking_overlapping_keys_of_a_multex() {
+    let multex = Multex8::new([0u8; 8]);
+    let mut key1 = Key::new([1, 2]).unwrap();
+    let mut key2 = Key::new([2, 3]).unwrap();
+    let _ = lock_many(((&multex, &mut key1), (&multex, &mut key2)));
+}
+
+#[test]
+#[should_panic]
+fn panics_when_ordering_a_fair_multex() {
+    let _ = Multex8A::<_, 2>::with_policy([0u8; 16], Fairness::Fifo, Strategy::Ordered);
+}
+
+#[test]
+fn serves_wide_keys_before_later_narrow_ones() {
+    for fairness in [Fairness::Fifo, Fairness::Reserve] {
+        serves_wide_key_first(fairness);
+    }
+}
+
+fn serves_wide_key_first(fairness: Fairness) {
+    let multex = Multex8::with_fairness([0u32; 8], fairness);
+    let order = std::sync::Mutex::new(Vec::new());
+    let mut key1 = Key::new([0]).unwrap();
+    let mut key2 = Key::new([1]).unwrap();
+    let guard = multex.lock_with(&mut key1, false).unwrap();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let _guard = multex.lock().unwrap();
+            order.lock().unwrap().push("wide");
+        });
+        // The free index 1 is turned away once the wide key waits for it.
+        while multex.try_lock_with(&mut key2, false).is_some() {
+            thread::yield_now();
+        }
+        scope.spawn(|| {
+            let mut key = Key::new([1]).unwrap();
+            let _guard = multex.lock_with(&mut key, false).unwrap();
+            order.lock().unwrap().push("narrow");
+        });
+        drop(guard);
+    });
+    assert_eq!(order.into_inner().unwrap(), ["wide", "narrow"]);
+}
+
+#[test]
+fn serves_higher_priorities_first() -> Result {
+    let multex = Multex8A::<_, 2>::new([0u8; 16]);
+    let order = std::sync::Mutex::new(Vec::new());
+    let mut key = Key::new([3, 12])?;
+    let guard = multex.lock_with(&mut key, false).unwrap();
+    thread::scope(|scope| {
+        for priority in [1, 9, 5] {
+            let (multex, order) = (&multex, &order);
+            scope.spawn(move || {
+                let mut key = Key::new([12]).unwrap();
+                let _guard = multex.lock_with_priority(&mut key, priority).unwrap();
+                order.lock().unwrap().push(priority);
+            });
+        }
+        while multex.queued() < 3 {
+            thread::yield_now();
+        }
+        drop(guard);
+    });
+    assert_eq!(order.into_inner()?, [9, 5, 1]);
+    Ok(())
+}
+
+#[cfg(feature = "deadlock")]
+#[test]
+fn detects_deadlocks_across_multexes() {
+    let multex1 = Multex8::new([0u8; 8]);
+    let multex2 = Multex64V::new(vec![0u8; 8]);
+    let barrier = Barrier::new(2);
+    let results = thread::scope(|scope| {
+        let handle1 = scope.spawn(|| {
+            let mut key1 = Key::new([1]).unwrap();
+            let mut key2 = Key::new([2]).unwrap();
+            let _guard1 = multex1.lock_with(&mut key1, false);
+            barrier.wait();
+            let _guard2 = multex2.lock_with(&mut key2, false);
+        });
+        let handle2 = scope.spawn(|| {
+            let mut key1 = Key::new([1]).unwrap();
+            let mut key2 = Key::new([2]).unwrap();
+            let _guard2 = multex2.lock_with(&mut key2, false);
+            barrier.wait();
+            let _guard1 = multex1.lock_with(&mut key1, false);
+        });
+        [handle1.join(), handle2.join()]
+    });
+    let ([Ok(()), Err(error)] | [Err(error), Ok(())]) = results else {
+        panic!()
+    };
+    let Some(report) = error.downcast_ref::<String>() else {
+        panic!()
+    };
+    assert!(report.starts_with("Deadlock detected."));
+}
+
+#[cfg(feature = "reentrant")]
+#[test]
+#[should_panic(expected = "Re-entrant lock detected.")]
+fn panics_when_relocking_held_indices() {
+    let multex = Multex32A::<_, 2>::new([0u8; 64]);
+    let mut key1 = Key::new([3, 40]).unwrap();
+    let mut key2 = Key::new([40]).unwrap();
+    let _guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let _guard2 = multex.lock_with(&mut key2, false).unwrap();
+}
+
+#[cfg(feature = "reentrant")]
+#[test]
+#[should_panic(expected = "Re-entrant lock detected.")]
+fn panics_when_waiting_for_held_indices_with_a_timeout() {
+    let multex = Multex32::new([0u8; 8]);
+    let mut key1 = Key::new([3, 5]).unwrap();
+    let key2 = Key::new([3, 5]).unwrap();
+    let _guard1 = multex.lock_with(&mut key1, false).unwrap();
+    let _claim = multex.acquire_any_with(&key2, Wait::after(Duration::from_secs(10)));
+}
+
 // #[test]
 // fn boba() {
 //     let multex = Multex8::new((1u8, 2u16));